@@ -1,16 +1,225 @@
+use std::convert::TryFrom;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
 
-#[derive(Debug, Clone)]
+use anyhow::{Context, Result};
+use indexmap::IndexMap;
+use libc::uid_t;
+use serde::Deserialize;
+
+use crate::user::{MAX_UID, MIN_UID};
+
+pub const DEFAULT_CONFIG_PATH: &str = "/etc/dsdmona/config.toml";
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
 pub struct Config {
     pub tty: u8,
     pub launch_type: LaunchType,
-    pub auto_login_session: Option<String>,
+    pub theme: ThemeKind,
+    /// Session which is selected when the user has no last session.
+    pub default_session: Option<String>,
+    /// Whether to ask for the session language after the password.
+    pub select_locale: bool,
+    /// Seconds between SIGTERM and SIGKILL when stopping a session or the X server.
+    pub termination_timeout: u64,
+    /// Variables of dsdmona's own environment which are passed to sessions and the X server.
+    pub pass_environment: Vec<String>,
+    /// Variables set in every session, in the order of the file. Values may refer to other variables
+    /// with `${VAR}`, including ones set earlier in the table.
+    pub environment: IndexMap<String, String>,
+    pub auth: AuthConfig,
+    pub users: UsersConfig,
+    pub sessions: SessionsConfig,
+    pub xserver: XServerConfig,
+    pub auto_login: AutoLoginConfig,
+    pub hooks: HooksConfig,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let data =
+            std::fs::read_to_string(path).with_context(|| format!("Failed to read config file {}", path.display()))?;
+        toml::from_str(&data).with_context(|| format!("Invalid config file {}", path.display()))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tty: 7,
+            launch_type: LaunchType::XInitRc,
+            theme: ThemeKind::Colorful,
+            default_session: None,
+            select_locale: true,
+            termination_timeout: 5,
+            pass_environment: vec!["TERM".to_owned()],
+            environment: IndexMap::new(),
+            auth: Default::default(),
+            users: Default::default(),
+            sessions: Default::default(),
+            xserver: Default::default(),
+            auto_login: Default::default(),
+            hooks: Default::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AuthConfig {
+    pub backend: AuthBackend,
+    /// PAM service name.
+    pub service: String,
+    /// PAM service name used for auto-login.
+    pub autologin_service: String,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            backend: AuthBackend::Pam,
+            service: "dsdmona".to_owned(),
+            autologin_service: "dsdmona-autologin".to_owned(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct UsersConfig {
+    pub min_uid: uid_t,
+    pub max_uid: uid_t,
+}
+
+impl Default for UsersConfig {
+    fn default() -> Self {
+        Self {
+            min_uid: MIN_UID,
+            max_uid: MAX_UID,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SessionsConfig {
+    /// Directories with `xsessions` and `wayland-sessions`, in order of precedence.
+    /// Defaults to `XDG_DATA_DIRS`.
+    pub data_dirs: Option<Vec<PathBuf>>,
+    /// Whether to include sessions from `~/.local/share` of the selected user.
+    pub user_sessions: bool,
+    /// Script used by the `xsession` launch type, e.g. `/etc/X11/xinit/Xsession` on Fedora.
+    pub xsession_script: PathBuf,
+}
+
+impl Default for SessionsConfig {
+    fn default() -> Self {
+        Self {
+            data_dirs: None,
+            user_sessions: false,
+            xsession_script: "/etc/X11/Xsession".into(),
+        }
+    }
+}
+
+impl SessionsConfig {
+    pub fn data_dirs(&self) -> Vec<PathBuf> {
+        if let Some(data_dirs) = &self.data_dirs {
+            return data_dirs.clone();
+        }
+
+        match std::env::var_os("XDG_DATA_DIRS") {
+            Some(data_dirs) if !data_dirs.is_empty() => std::env::split_paths(&data_dirs).collect(),
+            _ => vec!["/usr/local/share".into(), "/usr/share".into()],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct XServerConfig {
+    pub path: PathBuf,
+    /// Extra arguments passed to the X server.
+    pub args: Vec<String>,
+    /// Whether to pass `vtN`. Nested servers such as Xephyr don't accept it.
+    pub vt: bool,
+    /// Start `~/.xserverrc` as the user instead, like startx does. Requires a rootless X server.
+    pub user_xserverrc: bool,
+    /// Whether to accept TCP connections. Adds `-nolisten tcp` when disabled.
+    pub listen_tcp: bool,
+    pub keeptty: bool,
+    pub novtswitch: bool,
+    /// Seconds to wait for the server to accept connections.
+    pub startup_timeout: u64,
+    /// File which receives the server's stderr.
+    pub log_file: PathBuf,
+}
+
+impl Default for XServerConfig {
+    fn default() -> Self {
+        Self {
+            path: "/usr/bin/Xorg".into(),
+            args: Vec::new(),
+            vt: true,
+            user_xserverrc: false,
+            listen_tcp: false,
+            keeptty: false,
+            novtswitch: false,
+            startup_timeout: 10,
+            log_file: "/var/log/dsdmona/xserver.log".into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AutoLoginConfig {
+    pub user: Option<String>,
+    pub session: Option<String>,
+    pub mode: AutoLoginMode,
+    /// Seconds during which a key press cancels auto-login.
+    pub countdown: u32,
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Default, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AutoLoginMode {
+    /// Only the first login after boot.
+    #[default]
+    FirstBoot,
+    /// The first login after dsdmona starts, so that logging out or a failed auto-login shows the
+    /// greeter until dsdmona is restarted.
+    Always,
+}
+
+/// Commands executed as root around each user session.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct HooksConfig {
+    pub pre_session: Vec<String>,
+    pub post_session: Vec<String>,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ThemeKind {
+    Colorful,
+    Simple,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
 pub enum LaunchType {
     XInitRc,
     DBus,
+    /// Through the login shell of the user.
+    LoginShell,
+    /// Through the Xsession script of the distribution.
+    XSession,
+    /// Through `~/.xsession` of the user, which gets the session as arguments.
+    UserXSession,
 }
 
 impl FromStr for LaunchType {
@@ -20,7 +229,45 @@ impl FromStr for LaunchType {
         match s {
             "xinitrc" => Ok(Self::XInitRc),
             "dbus" => Ok(Self::DBus),
-            _ => anyhow::bail!("Unknown launch type"),
+            "shell" => Ok(Self::LoginShell),
+            "xsession" => Ok(Self::XSession),
+            "user-xsession" => Ok(Self::UserXSession),
+            _ => anyhow::bail!("Unknown launch type `{}`", s),
         }
     }
 }
+
+impl TryFrom<String> for LaunchType {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum AuthBackend {
+    Pam,
+    Shadow,
+}
+
+impl FromStr for AuthBackend {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pam" => Ok(Self::Pam),
+            "shadow" => Ok(Self::Shadow),
+            _ => anyhow::bail!("Unknown auth backend `{}`", s),
+        }
+    }
+}
+
+impl TryFrom<String> for AuthBackend {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
@@ -1,7 +1,8 @@
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use libc::uid_t;
@@ -9,23 +10,118 @@ use walkdir::WalkDir;
 
 use crate::user::User;
 
-const XORG_SESSIONS_PATH: &str = "/usr/share/xsessions";
 const LAST_SESSION_PATH: &str = ".cache/dsdmona/last_session";
+pub const USER_XSESSION_PATH: &str = ".xsession";
+const USER_XSESSION_ID: &str = "dsdmona-user-xsession";
 
 #[derive(Debug, Clone)]
 pub struct Desktop {
+    /// Desktop file ID without the `.desktop` suffix.
+    pub id: String,
+    pub path: PathBuf,
     pub name: String,
     pub comment: String,
+    pub icon: Option<String>,
     pub exec: String,
+    /// `exec` split into arguments with field codes expanded.
+    pub argv: Vec<String>,
+    /// Extra X server arguments from `X-Dsdmona-ServerArgs`, only read from entries owned by root.
+    pub server_args: Vec<String>,
+    pub try_exec: Option<String>,
+    pub hidden: bool,
+    pub no_display: bool,
+    /// Value of `XDG_CURRENT_DESKTOP`.
+    pub desktop_names: Vec<String>,
+    pub session_type: SessionType,
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Hash)]
+pub enum SessionType {
+    X11,
+    Wayland,
+}
+
+impl SessionType {
+    /// Value of `XDG_SESSION_TYPE`.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::X11 => "x11",
+            Self::Wayland => "wayland",
+        }
+    }
 }
 
 impl Desktop {
-    pub fn all() -> Vec<Self> {
-        WalkDir::new(XORG_SESSIONS_PATH)
+    /// Whether both are the same entry of the session list, even if loaded from different files.
+    pub fn is_same_session(&self, other: &Desktop) -> bool {
+        self.session_type == other.session_type && self.id == other.id
+    }
+
+    /// Loads sessions from `xsessions` and `wayland-sessions` of each data directory.
+    ///
+    /// Earlier directories take precedence over later ones for entries with the same desktop file ID.
+    pub fn all<P: AsRef<Path>>(data_dirs: &[P], locale: Option<&str>) -> Vec<Self> {
+        let mut desktops = Vec::new();
+        let mut ids = HashSet::new();
+
+        for data_dir in data_dirs {
+            for (dir, session_type) in [
+                ("xsessions", SessionType::X11),
+                ("wayland-sessions", SessionType::Wayland),
+            ] {
+                for desktop in Self::load_dir(data_dir.as_ref().join(dir), session_type, locale) {
+                    if ids.insert((desktop.session_type, desktop.id.clone())) {
+                        desktops.push(desktop);
+                    }
+                }
+            }
+        }
+
+        desktops.retain(Self::is_visible);
+        desktops
+    }
+
+    /// Synthetic "User script" session which runs `~/.xsession`, if it is executable.
+    pub fn user_xsession(home_dir: &Path) -> Option<Self> {
+        let path = home_dir.join(USER_XSESSION_PATH);
+        if !is_executable(&path) {
+            return None;
+        }
+
+        let exec = path.to_string_lossy().into_owned();
+        Some(Self {
+            id: USER_XSESSION_ID.to_owned(),
+            path: path.clone(),
+            name: "User script".to_owned(),
+            comment: "Runs ~/.xsession".to_owned(),
+            icon: None,
+            exec: exec.clone(),
+            argv: vec![exec],
+            server_args: Vec::new(),
+            try_exec: None,
+            hidden: false,
+            no_display: false,
+            desktop_names: Vec::new(),
+            session_type: SessionType::X11,
+        })
+    }
+
+    fn load_dir(dir: PathBuf, session_type: SessionType, locale: Option<&str>) -> Vec<Self> {
+        WalkDir::new(&dir)
+            .sort_by_file_name()
             .into_iter()
             .filter_map(|e| match e {
                 Ok(entry) if entry.file_type().is_file() => match entry.path().extension()?.to_str()? {
-                    "desktop" => Self::load(entry.path()).ok(),
+                    "desktop" => match Self::load(entry.path(), session_type, locale) {
+                        Ok(mut desktop) => {
+                            desktop.id = desktop_file_id(&dir, entry.path())?;
+                            Some(desktop)
+                        }
+                        Err(e) => {
+                            eprintln!("Skipping {}: {}", entry.path().display(), e);
+                            None
+                        }
+                    },
                     _ => None,
                 },
                 _ => None,
@@ -33,84 +129,589 @@ impl Desktop {
             .collect()
     }
 
-    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
-        let file = File::open(path)?;
-        let reader = BufReader::new(file);
-
-        let mut desktop = Desktop {
-            name: Default::default(),
-            comment: Default::default(),
-            exec: Default::default(),
-        };
+    pub fn load<P: AsRef<Path>>(path: P, session_type: SessionType, locale: Option<&str>) -> Result<Self> {
+        let file = File::open(&path)?;
+        let metadata = file.metadata()?;
+        let root_only = is_root_only(metadata.uid(), metadata.mode());
+        Self::parse(path.as_ref(), BufReader::new(file), session_type, locale, root_only)
+    }
 
-        let mut is_application = false;
+    /// Parses a desktop entry, honouring `X-Dsdmona-ServerArgs` only if the file is `root_only`.
+    fn parse<R: BufRead>(
+        path: &Path,
+        reader: R,
+        session_type: SessionType,
+        locale: Option<&str>,
+        root_only: bool,
+    ) -> Result<Self> {
+        let mut entries = HashMap::new();
 
         let mut desktop_entry_description = false;
         for item in reader.lines() {
             let line = item?;
             let line = line.trim();
 
-            if line.starts_with('#') {
+            if line.is_empty() || line.starts_with('#') {
                 continue;
             } else if line.starts_with('[') {
                 desktop_entry_description = line == "[Desktop Entry]";
             } else if desktop_entry_description {
-                if let Some(split) = line.find('=') {
-                    let (property, value) = line.split_at(split);
-                    let value = match value.find('#') {
-                        Some(split) => &value[1..split],
-                        None => &value[1..],
-                    };
-
-                    match property.to_lowercase().as_ref() {
-                        "type" => is_application = value == "Application",
-                        "name" => desktop.name = value.to_owned(),
-                        "comment" => desktop.comment = value.to_owned(),
-                        "exec" => desktop.exec = value.to_owned(),
-                        _ => {}
-                    }
+                if let Some((key, value)) = line.split_once('=') {
+                    entries.insert(key.trim_end().to_owned(), value.trim_start().to_owned());
                 }
             }
         }
 
-        anyhow::ensure!(is_application, "Invalid desktop entry. Type is not an `Application`");
+        let entry_type = entries.get("Type").map(String::as_str);
+        anyhow::ensure!(
+            matches!(entry_type, Some("Application" | "XSession")),
+            "Invalid desktop entry. Type is not an `Application`"
+        );
+
+        let get_localized = |key: &str| {
+            locale_variants(locale)
+                .iter()
+                .find_map(|locale| entries.get(&format!("{}[{}]", key, locale)))
+                .or_else(|| entries.get(key))
+                .map(|value| unescape(value))
+        };
+        let get_bool = |key: &str| entries.get(key).map(String::as_str) == Some("true");
+
+        let Some(name) = get_localized("Name") else {
+            anyhow::bail!("Invalid desktop entry. `Name` is missing");
+        };
+        let Some(exec) = entries.get("Exec").map(|value| unescape(value)) else {
+            anyhow::bail!("Invalid desktop entry. `Exec` is missing");
+        };
+
+        let mut desktop = Desktop {
+            id: path
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            path: path.to_owned(),
+            name,
+            comment: get_localized("Comment").unwrap_or_default(),
+            icon: get_localized("Icon"),
+            exec,
+            argv: Vec::new(),
+            server_args: Vec::new(),
+            try_exec: entries.get("TryExec").map(|value| unescape(value)),
+            hidden: get_bool("Hidden"),
+            no_display: get_bool("NoDisplay"),
+            desktop_names: entries
+                .get("DesktopNames")
+                .map(|value| split_list(value))
+                .unwrap_or_default(),
+            session_type,
+        };
+        desktop.argv = desktop.parse_command("Exec", &desktop.exec)?;
+        anyhow::ensure!(
+            desktop.argv.first().is_some_and(|bin| !bin.is_empty()),
+            "Empty `Exec` in {}",
+            desktop.path.display()
+        );
+
+        if let Some(server_args) = entries.get("X-Dsdmona-ServerArgs") {
+            if root_only {
+                desktop.server_args = desktop.parse_command("X-Dsdmona-ServerArgs", &unescape(server_args))?;
+            } else {
+                eprintln!(
+                    "Ignoring `X-Dsdmona-ServerArgs` of {}, which is not owned and only writable by root",
+                    desktop.path.display()
+                );
+            }
+        }
+
         Ok(desktop)
     }
+
+    /// Whether the session should be shown in the greeter.
+    pub fn is_visible(&self) -> bool {
+        if self.hidden || self.no_display {
+            return false;
+        }
+
+        match &self.try_exec {
+            Some(try_exec) => is_executable_in_path(try_exec),
+            None => true,
+        }
+    }
+
+    /// Splits a command line such as `Exec` according to the Desktop Entry Specification.
+    ///
+    /// File and URL field codes are removed because sessions are started without arguments.
+    fn parse_command(&self, key: &str, line: &str) -> Result<Vec<String>> {
+        let mut tokens = Vec::new();
+        let mut token = Vec::new();
+        let mut started = false;
+        let mut quoted = false;
+
+        let mut chars = line.chars();
+        while let Some(c) = chars.next() {
+            match c {
+                ' ' | '\t' | '\n' if !quoted => {
+                    if started {
+                        tokens.push(std::mem::take(&mut token));
+                        started = false;
+                    }
+                    continue;
+                }
+                '"' => quoted = !quoted,
+                '\\' => match chars.next() {
+                    Some(c @ ('"' | '`' | '$' | '\\')) => token.push(ExecPart::Char(c)),
+                    Some(c) if !quoted => token.push(ExecPart::Char(c)),
+                    Some(c) => anyhow::bail!("Invalid escape sequence `\\{}` in `{}` of {:?}", c, key, line),
+                    None => anyhow::bail!("Unexpected trailing backslash in `{}` of {:?}", key, line),
+                },
+                '%' => match chars.next() {
+                    Some('%') => token.push(ExecPart::Char('%')),
+                    Some(code @ ('f' | 'F' | 'u' | 'U' | 'd' | 'D' | 'n' | 'N' | 'i' | 'c' | 'k' | 'v' | 'm')) => {
+                        token.push(ExecPart::FieldCode(code))
+                    }
+                    Some(code) => anyhow::bail!("Unknown field code `%{}` in `{}` of {:?}", code, key, line),
+                    None => anyhow::bail!("Unexpected trailing `%` in `{}` of {:?}", key, line),
+                },
+                c => token.push(ExecPart::Char(c)),
+            }
+            started = true;
+        }
+
+        anyhow::ensure!(!quoted, "Unterminated quote in `{}` of {:?}", key, line);
+        if started {
+            tokens.push(token);
+        }
+
+        let mut argv = Vec::with_capacity(tokens.len());
+        for token in tokens {
+            if let [ExecPart::FieldCode(code)] = token.as_slice() {
+                match code {
+                    'i' => {
+                        if let Some(icon) = &self.icon {
+                            argv.push("--icon".to_owned());
+                            argv.push(icon.clone());
+                        }
+                    }
+                    'c' => argv.push(self.name.clone()),
+                    'k' => argv.push(self.path.to_string_lossy().into_owned()),
+                    _ => {}
+                }
+                continue;
+            }
+
+            let mut arg = String::new();
+            for part in token {
+                match part {
+                    ExecPart::Char(c) => arg.push(c),
+                    ExecPart::FieldCode('c') => arg.push_str(&self.name),
+                    ExecPart::FieldCode('k') => arg.push_str(&self.path.to_string_lossy()),
+                    ExecPart::FieldCode(_) => {}
+                }
+            }
+            argv.push(arg);
+        }
+
+        Ok(argv)
+    }
+}
+
+enum ExecPart {
+    Char(char),
+    FieldCode(char),
+}
+
+/// Path relative to the session directory with `/` replaced by `-`, e.g. `kde/plasma`
+/// becomes `kde-plasma`.
+fn desktop_file_id(dir: &Path, path: &Path) -> Option<String> {
+    let relative = path.strip_prefix(dir).ok()?.with_extension("");
+    let id = relative.to_str()?.replace('/', "-");
+    Some(id)
+}
+
+/// Locale keys to look up for `lang_COUNTRY.ENCODING@MODIFIER`, most specific first.
+fn locale_variants(locale: Option<&str>) -> Vec<String> {
+    let Some(locale) = locale else {
+        return Vec::new();
+    };
+
+    let (locale, modifier) = match locale.split_once('@') {
+        Some((locale, modifier)) => (locale, Some(modifier)),
+        None => (locale, None),
+    };
+    let locale = locale.split('.').next().unwrap_or_default();
+    let (lang, country) = match locale.split_once('_') {
+        Some((lang, country)) => (lang, Some(country)),
+        None => (locale, None),
+    };
+
+    let mut variants = Vec::with_capacity(4);
+    if let (Some(country), Some(modifier)) = (country, modifier) {
+        variants.push(format!("{}_{}@{}", lang, country, modifier));
+    }
+    if let Some(country) = country {
+        variants.push(format!("{}_{}", lang, country));
+    }
+    if let Some(modifier) = modifier {
+        variants.push(format!("{}@{}", lang, modifier));
+    }
+    if !lang.is_empty() && lang != "C" && lang != "POSIX" {
+        variants.push(lang.to_owned());
+    }
+    variants
+}
+
+/// Resolves `\s`, `\n`, `\t`, `\r` and `\\` escapes. Unknown escapes are kept as is.
+fn unescape(value: &str) -> String {
+    let mut result = String::with_capacity(value.len());
+
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+
+        match chars.next() {
+            Some('s') => result.push(' '),
+            Some('n') => result.push('\n'),
+            Some('t') => result.push('\t'),
+            Some('r') => result.push('\r'),
+            Some('\\') => result.push('\\'),
+            Some(c) => {
+                result.push('\\');
+                result.push(c);
+            }
+            None => result.push('\\'),
+        }
+    }
+
+    result
 }
 
+/// Splits a `;` separated list, honoring `\;` escapes.
+fn split_list(value: &str) -> Vec<String> {
+    let mut items = Vec::new();
+    let mut item = String::new();
+
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ';' => items.push(unescape(&std::mem::take(&mut item))),
+            '\\' => match chars.next() {
+                Some(';') => item.push(';'),
+                Some(c) => {
+                    item.push('\\');
+                    item.push(c);
+                }
+                None => item.push('\\'),
+            },
+            c => item.push(c),
+        }
+    }
+    if !item.is_empty() {
+        items.push(unescape(&item));
+    }
+
+    items.retain(|item| !item.is_empty());
+    items
+}
+
+/// Whether a file is owned by root and writable by nobody else. The X server runs as root, so only
+/// root may choose its arguments.
+fn is_root_only(uid: uid_t, mode: u32) -> bool {
+    uid == 0 && mode & 0o022 == 0
+}
+
+pub fn is_executable(path: &Path) -> bool {
+    match std::fs::metadata(path) {
+        Ok(metadata) => metadata.is_file() && metadata.permissions().mode() & 0o111 != 0,
+        Err(_) => false,
+    }
+}
+
+fn is_executable_in_path(program: &str) -> bool {
+    if program.contains('/') {
+        return is_executable(Path::new(program));
+    }
+
+    let path = std::env::var_os("PATH").unwrap_or_else(|| "/usr/local/bin:/usr/bin:/bin".into());
+    std::env::split_paths(&path).any(|dir| is_executable(&dir.join(program)))
+}
+
+/// The last session of a user, identified like the greeter lists it: by session type and
+/// desktop file ID. Stored as `<type> <id>`, e.g. `wayland gnome`.
 pub struct LastSession {
     pub uid: uid_t,
-    pub exec: String,
+    pub session_type: SessionType,
+    pub id: String,
+}
+
+impl LastSession {
+    pub fn is(&self, desktop: &Desktop) -> bool {
+        desktop.session_type == self.session_type && desktop.id == self.id
+    }
 }
 
 impl User {
     pub fn get_last_desktop<'a>(&self, desktops: &'a [Desktop]) -> Option<&'a Desktop> {
         let last_session = self.get_last_session()?;
-        desktops.iter().find(|desktop| desktop.exec == last_session.exec)
+        desktops.iter().find(|desktop| last_session.is(desktop))
     }
 
     pub fn get_last_session(&self) -> Option<LastSession> {
+        let _fs = self.fs_guard().ok()?;
+
         let path = self.home_dir().join(LAST_SESSION_PATH);
         if !path.exists() {
             return None;
         }
 
         let last_session = std::fs::read_to_string(path).ok()?;
-        let exec = last_session.trim();
+        let (session_type, id) = last_session.trim().split_once(' ')?;
+        let session_type = match session_type {
+            "x11" => SessionType::X11,
+            "wayland" => SessionType::Wayland,
+            _ => return None,
+        };
 
         Some(LastSession {
             uid: self.uid(),
-            exec: exec.to_owned(),
+            session_type,
+            id: id.to_owned(),
         })
     }
 
     pub fn set_last_session(&self, desktop: &Desktop) -> Result<()> {
-        self.use_for_fs(|| {
-            let path = self.home_dir().join(LAST_SESSION_PATH);
-            std::fs::create_dir_all(&path)?;
-            std::fs::write(&path, &desktop.exec)?;
-            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o744))?;
-            Ok(())
-        })
+        let _fs = self.fs_guard()?;
+
+        let path = self.home_dir().join(LAST_SESSION_PATH);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, format!("{} {}", desktop.session_type.as_str(), desktop.id))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::fixture;
+
+    fn load(path: &str, locale: Option<&str>) -> Desktop {
+        Desktop::load(fixture(path), SessionType::X11, locale).unwrap()
+    }
+
+    fn strings(values: &[&str]) -> Vec<String> {
+        values.iter().map(|value| value.to_string()).collect()
+    }
+
+    #[test]
+    fn load_gnome() {
+        let desktop = load("sessions/xsessions/gnome-xorg.desktop", None);
+
+        assert_eq!(desktop.id, "gnome-xorg");
+        assert_eq!(desktop.name, "GNOME on Xorg");
+        assert_eq!(desktop.comment, "This session logs you into GNOME, using Xorg");
+        assert_eq!(desktop.exec, "/usr/bin/gnome-session");
+        assert_eq!(desktop.argv, strings(&["/usr/bin/gnome-session"]));
+        assert_eq!(desktop.try_exec.as_deref(), Some("sh"));
+        assert_eq!(desktop.desktop_names, strings(&["GNOME"]));
+        assert_eq!(desktop.session_type, SessionType::X11);
+        assert!(desktop.is_visible());
+    }
+
+    #[test]
+    fn load_plasma_of_type_xsession() {
+        let desktop = load("sessions/xsessions/plasmax11.desktop", None);
+
+        assert_eq!(desktop.name, "Plasma (X11)");
+        assert_eq!(desktop.argv, strings(&["/usr/bin/startplasma-x11"]));
+        assert_eq!(desktop.desktop_names, strings(&["KDE"]));
+    }
+
+    #[test]
+    fn load_sway() {
+        let desktop = Desktop::load(
+            fixture("sessions/wayland-sessions/sway.desktop"),
+            SessionType::Wayland,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(desktop.argv, strings(&["sway"]));
+        assert_eq!(desktop.desktop_names, strings(&["sway", "wlroots"]));
+        assert_eq!(desktop.session_type, SessionType::Wayland);
+    }
+
+    #[test]
+    fn load_localized_keys() {
+        let gnome = "sessions/xsessions/gnome-xorg.desktop";
+        assert_eq!(load(gnome, Some("de_DE.UTF-8")).name, "GNOME auf Xorg");
+        assert_eq!(
+            load(gnome, Some("de_AT.UTF-8")).comment,
+            "Diese Sitzung meldet Sie bei GNOME an und verwendet Xorg"
+        );
+        assert_eq!(load(gnome, Some("pt_BR.UTF-8")).name, "GNOME no Xorg");
+        assert_eq!(load(gnome, Some("pt_PT.UTF-8")).name, "GNOME on Xorg");
+        assert_eq!(load(gnome, Some("C.UTF-8")).name, "GNOME on Xorg");
+
+        let plasma = "sessions/xsessions/plasmax11.desktop";
+        assert_eq!(load(plasma, Some("sr_RS@latin")).name, "Plasma (X11, latinica)");
+        assert_eq!(load(plasma, Some("sr_RS.UTF-8")).name, "Плазма (X11)");
+    }
+
+    /// Parses a fixture as if it was, or wasn't, owned and only writable by root.
+    fn parse(path: &str, root_only: bool) -> Desktop {
+        let path = fixture(path);
+        let reader = BufReader::new(File::open(&path).unwrap());
+        Desktop::parse(&path, reader, SessionType::X11, None, root_only).unwrap()
+    }
+
+    #[test]
+    fn load_escapes_and_field_codes() {
+        let desktop = parse("sessions/xsessions/escapes.desktop", true);
+        let path = fixture("sessions/xsessions/escapes.desktop");
+
+        assert_eq!(desktop.name, "Escaped Name");
+        assert_eq!(desktop.comment, "Line one\nLine two\\n");
+        assert_eq!(
+            desktop.argv,
+            strings(&[
+                "/opt/my session/run",
+                "--title",
+                "a \"quoted\" $HOME \\ `cmd`",
+                "100%",
+                "--name=Escaped Name",
+                "--icon",
+                "my-session",
+                path.to_str().unwrap(),
+            ])
+        );
+        assert_eq!(desktop.desktop_names, strings(&["Escaped;Desktop", "Other"]));
+        assert_eq!(desktop.server_args, strings(&["-dpi", "192", "-config", "custom.conf"]));
+    }
+
+    #[test]
+    fn server_args_need_a_root_only_file() {
+        let desktop = parse("sessions/xsessions/escapes.desktop", false);
+        assert_eq!(desktop.argv.first().map(String::as_str), Some("/opt/my session/run"));
+        assert!(desktop.server_args.is_empty());
+
+        assert!(is_root_only(0, 0o100644));
+        assert!(is_root_only(0, 0o100700));
+        assert!(!is_root_only(1000, 0o100644));
+        assert!(!is_root_only(0, 0o100664));
+        assert!(!is_root_only(0, 0o100646));
+    }
+
+    #[test]
+    fn load_rejects_invalid_entries() {
+        let e = Desktop::load(fixture("sessions/xsessions/invalid.desktop"), SessionType::X11, None).unwrap_err();
+        assert_eq!(e.to_string(), "Invalid desktop entry. `Exec` is missing");
+    }
+
+    #[test]
+    fn visibility() {
+        assert!(!load("sessions/xsessions/hidden.desktop", None).is_visible());
+        assert!(!load("sessions/xsessions/nodisplay.desktop", None).is_visible());
+        assert!(!load("sessions/xsessions/not-installed.desktop", None).is_visible());
+        assert!(load("sessions/xsessions/kde/custom.desktop", None).is_visible());
+    }
+
+    #[test]
+    fn all_sessions() {
+        let desktops = Desktop::all(&[fixture("sessions-override"), fixture("sessions")], None);
+        let ids = desktops
+            .iter()
+            .map(|desktop| (desktop.session_type, desktop.id.as_str(), desktop.name.as_str()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            ids,
+            vec![
+                (SessionType::Wayland, "sway", "Sway (override)"),
+                (SessionType::X11, "escapes", "Escaped Name"),
+                (SessionType::X11, "gnome-xorg", "GNOME on Xorg"),
+                (SessionType::X11, "kde-custom", "Custom Plasma"),
+                (SessionType::X11, "plasmax11", "Plasma (X11)"),
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_command_errors() {
+        let desktop = load("sessions/xsessions/gnome-xorg.desktop", None);
+        let error = |line: &str| desktop.parse_command("Exec", line).unwrap_err().to_string();
+
+        assert_eq!(
+            error(r#"run "unterminated"#),
+            r#"Unterminated quote in `Exec` of "run \"unterminated""#
+        );
+        assert_eq!(error("run %x"), r#"Unknown field code `%x` in `Exec` of "run %x""#);
+        assert_eq!(error("run 100%"), r#"Unexpected trailing `%` in `Exec` of "run 100%""#);
+        assert_eq!(
+            error(r#"run "\a""#),
+            r#"Invalid escape sequence `\a` in `Exec` of "run \"\\a\"""#
+        );
+        assert_eq!(
+            error(r"run \"),
+            r#"Unexpected trailing backslash in `Exec` of "run \\""#
+        );
+    }
+
+    #[test]
+    fn parse_command_splitting() {
+        let desktop = load("sessions/xsessions/gnome-xorg.desktop", None);
+        let parse = |line: &str| desktop.parse_command("Exec", line).unwrap();
+
+        assert_eq!(parse("  a\tb  c\n"), strings(&["a", "b", "c"]));
+        assert_eq!(parse("a \"\" b"), strings(&["a", "", "b"]));
+        assert_eq!(parse("a\"b c\"d"), strings(&["ab cd"]));
+        assert_eq!(parse(r"a\ b"), strings(&["a b"]));
+        assert_eq!(parse("run %f %F %u %U %d %D %n %N %v %m"), strings(&["run"]));
+        // The entry has no icon, so `%i` expands to nothing
+        assert_eq!(parse("run %i"), strings(&["run"]));
+        assert_eq!(parse("run --name=%c"), strings(&["run", "--name=GNOME on Xorg"]));
+    }
+
+    #[test]
+    fn unescape_values() {
+        assert_eq!(unescape(r"a\sb\nc\td\re\\f"), "a b\nc\td\re\\f");
+        assert_eq!(unescape(r"keep \; and \x"), r"keep \; and \x");
+        assert_eq!(unescape(r"trailing \"), r"trailing \");
+    }
+
+    #[test]
+    fn split_lists() {
+        assert_eq!(split_list("GNOME;"), strings(&["GNOME"]));
+        assert_eq!(split_list("a;b;;c"), strings(&["a", "b", "c"]));
+        assert_eq!(split_list(r"a\;b;c\sd"), strings(&["a;b", "c d"]));
+        assert_eq!(split_list(""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn locale_lookup_order() {
+        assert_eq!(
+            locale_variants(Some("sr_RS.UTF-8@latin")),
+            strings(&["sr_RS@latin", "sr_RS", "sr@latin", "sr"])
+        );
+        assert_eq!(locale_variants(Some("de_DE.UTF-8")), strings(&["de_DE", "de"]));
+        assert_eq!(locale_variants(Some("eo")), strings(&["eo"]));
+        assert_eq!(locale_variants(Some("C.UTF-8")), Vec::<String>::new());
+        assert_eq!(locale_variants(Some("POSIX")), Vec::<String>::new());
+        assert_eq!(locale_variants(None), Vec::<String>::new());
+    }
+
+    #[test]
+    fn desktop_file_ids() {
+        let dir = Path::new("/usr/share/xsessions");
+        assert_eq!(
+            desktop_file_id(dir, &dir.join("kde/plasma.desktop")).as_deref(),
+            Some("kde-plasma")
+        );
+        assert_eq!(desktop_file_id(dir, Path::new("/elsewhere/a.desktop")), None);
     }
 }
@@ -0,0 +1,676 @@
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use anyhow::Result;
+
+use crate::config::Config;
+use crate::desktop::Desktop;
+use crate::locale;
+use crate::user::User;
+
+const ENVIRONMENT_PATH: &str = "/etc/environment";
+const PAM_ENVIRONMENT_PATH: &str = ".pam_environment";
+
+const MAIL_DIR: &str = "/var/mail";
+const AUDIT_SESSION_ID_PATH: &str = "/proc/self/sessionid";
+/// `(u32)-1`, the session id of processes which never logged in.
+const UNSET_AUDIT_SESSION_ID: &str = "4294967295";
+
+const DEFAULT_TERM: &str = "linux";
+const DEFAULT_CONFIG_DIRS: &str = "/etc/xdg";
+const DEFAULT_DATA_DIRS: &str = "/usr/local/share:/usr/share";
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+const DEFAULT_ROOT_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+
+/// Environment of a user session.
+///
+/// Processes started as the user get exactly these variables, nothing is inherited from dsdmona
+/// except for the variables listed in `pass_environment` (by default only `TERM`).
+///
+/// Later layers override earlier ones:
+/// 1. `pass_environment`, `MAIL` and the defaults for `TERM`, `PATH` and the XDG base directories
+/// 2. `/etc/environment`
+/// 3. `/etc/default/locale` and `/etc/locale.conf`
+/// 4. `LANG` of the selected locale
+/// 5. The `[environment]` table of the config
+/// 6. `~/.pam_environment`
+/// 7. Variables describing the session, such as `HOME` or `XDG_SESSION_TYPE`
+pub struct Env {
+    pub runtime_dir: String,
+    pub variables: HashMap<String, OsString>,
+}
+
+impl Env {
+    pub fn define(user: &User, desktop: &Desktop, locale: &str, config: &Config) -> Result<Self> {
+        let runtime_dir = runtime_dir(user);
+
+        // Files in the home directory are read with the permissions of the user
+        let variables = {
+            let _fs = user.fs_guard()?;
+            let sources = Sources::system(user);
+            variables(user, desktop, locale, config, &sources, &|key| std::env::var_os(key))
+        };
+
+        if !Path::new(&runtime_dir).exists() {
+            std::fs::create_dir_all(&runtime_dir)?;
+        }
+        user.set_file_owner(&runtime_dir)?;
+        std::fs::set_permissions(&runtime_dir, std::fs::Permissions::from_mode(0o700))?;
+
+        {
+            let _fs = user.fs_guard()?;
+            std::env::set_current_dir(user.home_dir())?;
+        }
+
+        Ok(Self { runtime_dir, variables })
+    }
+
+    /// Replaces the whole environment of the command with the session's.
+    pub fn apply(&self, command: &mut Command) {
+        command.env_clear().envs(&self.variables);
+    }
+
+    /// Sets `XDG_SESSION_ID` from the audit session, unless PAM (pam_systemd) has provided it.
+    ///
+    /// Must be called after the PAM session has been opened, since pam_loginuid starts a new
+    /// audit session.
+    pub fn set_session_id(&mut self) {
+        if self.variables.contains_key("XDG_SESSION_ID") {
+            return;
+        }
+
+        let Ok(session_id) = std::fs::read_to_string(AUDIT_SESSION_ID_PATH) else {
+            return;
+        };
+        let session_id = session_id.trim();
+        if !session_id.is_empty() && session_id != UNSET_AUDIT_SESSION_ID {
+            self.variables.insert("XDG_SESSION_ID".into(), session_id.into());
+        }
+    }
+}
+
+/// Variables of dsdmona which are allowed to reach sessions and the X server.
+pub fn passed_through(config: &Config) -> HashMap<String, OsString> {
+    pass_through(config, &|key| std::env::var_os(key))
+}
+
+fn pass_through(config: &Config, daemon_env: &dyn Fn(&str) -> Option<OsString>) -> HashMap<String, OsString> {
+    config
+        .pass_environment
+        .iter()
+        .filter_map(|key| Some((key.clone(), daemon_env(key)?)))
+        .collect()
+}
+
+/// Files the session environment is loaded from.
+struct Sources {
+    environment: PathBuf,
+    /// In increasing precedence.
+    locale: Vec<PathBuf>,
+    pam_environment: PathBuf,
+}
+
+impl Sources {
+    fn system(user: &User) -> Self {
+        Self {
+            environment: ENVIRONMENT_PATH.into(),
+            locale: locale::LOCALE_PATHS.iter().map(PathBuf::from).collect(),
+            pam_environment: user.home_dir().join(PAM_ENVIRONMENT_PATH),
+        }
+    }
+}
+
+/// All variables of the session, in the layers described at [`Env`].
+///
+/// `daemon_env` looks up variables of dsdmona's own environment.
+fn variables(
+    user: &User,
+    desktop: &Desktop,
+    locale: &str,
+    config: &Config,
+    sources: &Sources,
+    daemon_env: &dyn Fn(&str) -> Option<OsString>,
+) -> HashMap<String, OsString> {
+    let mut env = pass_through(config, daemon_env);
+    env.insert("PATH".into(), default_path(user).into());
+    env.entry("TERM".into()).or_insert_with(|| DEFAULT_TERM.into());
+    env.insert("MAIL".into(), Path::new(MAIL_DIR).join(user.name()).into());
+
+    // XDG Base Directory Specification
+    env.insert("XDG_CONFIG_HOME".into(), user.home_dir().join(".config").into());
+    env.insert("XDG_DATA_HOME".into(), user.home_dir().join(".local/share").into());
+    env.insert("XDG_STATE_HOME".into(), user.home_dir().join(".local/state").into());
+    env.insert("XDG_CACHE_HOME".into(), user.home_dir().join(".cache").into());
+    env.insert("XDG_CONFIG_DIRS".into(), DEFAULT_CONFIG_DIRS.into());
+    env.insert("XDG_DATA_DIRS".into(), DEFAULT_DATA_DIRS.into());
+
+    load_file(&mut env, user, &sources.environment);
+    for path in &sources.locale {
+        load_file(&mut env, user, path);
+    }
+    set_locale(&mut env, locale);
+    for (key, value) in &config.environment {
+        let value = expand(value, &env, user);
+        env.insert(key.clone(), value.into());
+    }
+    load_pam_environment(&mut env, user, &sources.pam_environment);
+
+    env.insert("HOME".into(), user.home_dir().into());
+    env.insert("PWD".into(), user.home_dir().into());
+    env.insert("USER".into(), user.name().into());
+    env.insert("LOGNAME".into(), user.name().into());
+    env.insert("XDG_RUNTIME_DIR".into(), runtime_dir(user).into());
+    env.insert("XDG_SEAT".into(), "seat0".into());
+    env.insert("XDG_VTNR".into(), config.tty.to_string().into());
+    env.insert("XDG_SESSION_CLASS".into(), "user".into());
+    env.insert("SHELL".into(), user.shell().into());
+
+    // Display managers identify sessions by the ID of their desktop entry, not by its name
+    env.insert("DESKTOP_SESSION".into(), desktop.id.clone().into());
+    env.insert("XDG_SESSION_DESKTOP".into(), desktop.id.clone().into());
+    env.insert("XDG_SESSION_TYPE".into(), desktop.session_type.as_str().into());
+    if !desktop.desktop_names.is_empty() {
+        env.insert("XDG_CURRENT_DESKTOP".into(), desktop.desktop_names.join(":").into());
+    }
+
+    env
+}
+
+fn runtime_dir(user: &User) -> String {
+    format!("/run/user/{}", user.uid())
+}
+
+/// Sets `LANG`, dropping the other locale variables unless they were meant for the same locale.
+fn set_locale(env: &mut HashMap<String, OsString>, locale: &str) {
+    let system = env.get("LANG").and_then(|lang| lang.to_str());
+    if !system.is_some_and(|system| locale::is_same(system, locale)) {
+        env.retain(|key, _| !key.starts_with("LC_") && key != "LANGUAGE");
+    }
+    env.insert("LANG".into(), locale.into());
+}
+
+/// Loads `KEY=VALUE` lines, as used by `/etc/environment` and the locale files.
+fn load_file(env: &mut HashMap<String, OsString>, user: &User, path: &Path) {
+    let Some(data) = read_optional(path) else {
+        return;
+    };
+
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+        match line.split_once('=') {
+            Some((key, value)) if is_valid_key(key) => {
+                let value = expand(unquote(value.trim()), env, user);
+                env.insert(key.to_owned(), value.into());
+            }
+            _ => eprintln!("Skipping invalid line in {}: {}", path.display(), line),
+        }
+    }
+}
+
+/// Loads a pam_env user file, which has lines in either the `KEY=VALUE` or the
+/// `KEY [DEFAULT=value] [OVERRIDE=value]` form.
+fn load_pam_environment(env: &mut HashMap<String, OsString>, user: &User, path: &Path) {
+    let Some(data) = read_optional(path) else {
+        return;
+    };
+
+    for line in data.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut words = split_words(line).into_iter();
+        let Some(first) = words.next() else {
+            continue;
+        };
+
+        if let Some((key, value)) = first.split_once('=') {
+            if is_valid_key(key) {
+                let value = expand(value, env, user);
+                env.insert(key.to_owned(), value.into());
+                continue;
+            }
+        }
+        if !is_valid_key(&first) {
+            eprintln!("Skipping invalid line in {}: {}", path.display(), line);
+            continue;
+        }
+
+        let (mut default, mut override_value) = (None, None);
+        for word in words {
+            if let Some(value) = word.strip_prefix("DEFAULT=") {
+                default = Some(expand(value, env, user));
+            } else if let Some(value) = word.strip_prefix("OVERRIDE=") {
+                override_value = Some(expand(value, env, user));
+            } else {
+                eprintln!("Skipping unknown option {} in {}", word, path.display());
+            }
+        }
+
+        // An empty OVERRIDE falls back to DEFAULT, and without any value the variable is removed
+        match override_value.filter(|value| !value.is_empty()).or(default) {
+            Some(value) if !value.is_empty() => {
+                env.insert(first, value.into());
+            }
+            _ => {
+                env.remove(&first);
+            }
+        }
+    }
+}
+
+/// Expands `${VAR}` from the variables defined so far, as well as `@{HOME}` and `@{SHELL}` of
+/// the user. Unknown variables expand to nothing and `\$` or `\@` keep the character.
+fn expand(value: &str, env: &HashMap<String, OsString>, user: &User) -> String {
+    let mut result = String::with_capacity(value.len());
+
+    let mut chars = value.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' if matches!(chars.peek(), Some('$' | '@' | '\\')) => result.extend(chars.next()),
+            '$' | '@' if chars.peek() == Some(&'{') => {
+                chars.next();
+                let name = chars.by_ref().take_while(|&c| c != '}').collect::<String>();
+                match (c, name.as_str()) {
+                    ('$', _) => {
+                        if let Some(value) = env.get(&name) {
+                            result.push_str(&value.to_string_lossy());
+                        }
+                    }
+                    ('@', "HOME") => result.push_str(&user.home_dir().to_string_lossy()),
+                    ('@', "SHELL") => result.push_str(&user.shell().to_string_lossy()),
+                    _ => {}
+                }
+            }
+            c => result.push(c),
+        }
+    }
+
+    result
+}
+
+/// Splits on whitespace outside of double quotes, removing the quotes.
+fn split_words(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut quoted = false;
+
+    for c in line.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+            }
+            c => word.push(c),
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+
+    words
+}
+
+fn unquote(value: &str) -> &str {
+    for quote in ['"', '\''] {
+        if let Some(value) = value.strip_prefix(quote).and_then(|value| value.strip_suffix(quote)) {
+            return value;
+        }
+    }
+    value
+}
+
+fn is_valid_key(key: &str) -> bool {
+    !key.is_empty()
+        && !key.starts_with(|c: char| c.is_ascii_digit())
+        && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn read_optional(path: &Path) -> Option<String> {
+    match std::fs::read_to_string(path) {
+        Ok(data) => Some(data),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+fn default_path(user: &User) -> &'static str {
+    match user.uid() {
+        0 => DEFAULT_ROOT_PATH,
+        _ => DEFAULT_PATH,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::desktop::SessionType;
+    use crate::testing::fixture;
+
+    fn sample_user() -> User {
+        User::sample(1000, "alice", "/home/alice", "/bin/zsh")
+    }
+
+    /// No files at all, so that only the config and dsdmona's environment contribute.
+    fn no_sources() -> Sources {
+        Sources {
+            environment: fixture("env/missing/environment"),
+            locale: vec![fixture("env/missing/locale.conf")],
+            pam_environment: fixture("env/missing/.pam_environment"),
+        }
+    }
+
+    fn sway() -> Desktop {
+        Desktop::load(
+            fixture("sessions/wayland-sessions/sway.desktop"),
+            SessionType::Wayland,
+            None,
+        )
+        .unwrap()
+    }
+
+    /// Environment of a daemon started by systemd, plus variables which must never leak.
+    fn daemon_env(key: &str) -> Option<OsString> {
+        let value = match key {
+            "TERM" => "vt220",
+            "TZ" => ":Europe/Berlin",
+            "LANG" => "de_DE.UTF-8",
+            "PATH" => "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin",
+            "INVOCATION_ID" => "5b3b1ab5e0b54f4e9d9ca1df6c22d5b5",
+            "JOURNAL_STREAM" => "8:21938",
+            "NOTIFY_SOCKET" => "/run/systemd/notify",
+            "LD_PRELOAD" => "/tmp/evil.so",
+            "HOME" => "/root",
+            "DISPLAY" => ":0",
+            "DSDMONA_SECRET" => "secret",
+            _ => return None,
+        };
+        Some(value.into())
+    }
+
+    const DAEMON_KEYS: [&str; 11] = [
+        "TERM",
+        "TZ",
+        "LANG",
+        "PATH",
+        "INVOCATION_ID",
+        "JOURNAL_STREAM",
+        "NOTIFY_SOCKET",
+        "LD_PRELOAD",
+        "HOME",
+        "DISPLAY",
+        "DSDMONA_SECRET",
+    ];
+
+    #[test]
+    fn only_allowed_daemon_variables_reach_the_session() {
+        let config = Config {
+            pass_environment: vec!["TERM".to_owned(), "TZ".to_owned()],
+            ..Config::default()
+        };
+        let env = variables(&sample_user(), &sway(), "C.UTF-8", &config, &no_sources(), &daemon_env);
+
+        for key in DAEMON_KEYS {
+            let leaked = env.get(key) == daemon_env(key).as_ref();
+            assert_eq!(
+                leaked,
+                config.pass_environment.iter().any(|allowed| allowed == key),
+                "{}",
+                key
+            );
+        }
+    }
+
+    #[test]
+    fn term_defaults_to_linux_unless_passed() {
+        let config = Config {
+            pass_environment: Vec::new(),
+            ..Config::default()
+        };
+        let env = variables(&sample_user(), &sway(), "C.UTF-8", &config, &no_sources(), &daemon_env);
+        assert_eq!(env["TERM"], "linux");
+
+        let env = variables(
+            &sample_user(),
+            &sway(),
+            "C.UTF-8",
+            &Config::default(),
+            &no_sources(),
+            &daemon_env,
+        );
+        assert_eq!(env["TERM"], "vt220");
+    }
+
+    fn fixture_user() -> User {
+        User::sample(1000, "alice", fixture("env/home").to_str().unwrap(), "/bin/zsh")
+    }
+
+    fn fixture_sources() -> Sources {
+        Sources {
+            environment: fixture("env/environment"),
+            locale: vec![fixture("env/default-locale"), fixture("env/locale.conf")],
+            pam_environment: fixture("env/home/.pam_environment"),
+        }
+    }
+
+    fn fixture_config() -> Config {
+        let environment = [
+            ("EDITOR", "vim"),
+            ("VISUAL", "${EDITOR}"),
+            ("LESS", "-R"),
+            ("HOME", "/config/home"),
+        ];
+        Config {
+            pass_environment: vec!["TERM".to_owned(), "TZ".to_owned()],
+            environment: environment
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect(),
+            ..Config::default()
+        }
+    }
+
+    #[test]
+    fn layers_override_each_other_in_order() {
+        let user = fixture_user();
+        let home = user.home_dir().to_str().unwrap().to_owned();
+        let env = variables(
+            &user,
+            &sway(),
+            "en_US.utf8",
+            &fixture_config(),
+            &fixture_sources(),
+            &daemon_env,
+        );
+        let get = |key: &str| env.get(key).map(|value| value.to_str().unwrap().to_owned());
+
+        // 1. Passed through from dsdmona, and defaults
+        assert_eq!(get("TERM").as_deref(), Some("vt220"));
+        assert_eq!(get("MAIL").as_deref(), Some("/var/mail/alice"));
+        // 2. /etc/environment overrides them
+        assert_eq!(
+            get("XDG_DATA_DIRS").as_deref(),
+            Some("/usr/share:/var/lib/flatpak/exports/share")
+        );
+        // 3. locale.conf overrides /etc/default/locale, which overrides /etc/environment
+        assert_eq!(get("LC_TIME").as_deref(), Some("en_DK.UTF-8"));
+        assert_eq!(get("LC_PAPER").as_deref(), Some("en_GB.UTF-8"));
+        assert_eq!(get("LANGUAGE").as_deref(), Some("en_GB:en"));
+        // 4. The selected locale, which keeps the system's LC_* since it is the same locale
+        assert_eq!(get("LANG").as_deref(), Some("en_US.utf8"));
+        // 5. The config, which may refer to earlier variables
+        assert_eq!(get("VISUAL").as_deref(), Some("vim"));
+        assert_eq!(get("LESS").as_deref(), Some("-R"));
+        // 6. ~/.pam_environment
+        assert_eq!(get("EDITOR").as_deref(), Some("emacs"));
+        assert_eq!(get("PAGER").as_deref(), Some("most"));
+        assert_eq!(get("BROWSER").as_deref(), Some("firefox"));
+        assert_eq!(get("GOPATH"), Some(format!("{}/go", home)));
+        assert_eq!(
+            get("PATH"),
+            Some(format!("/usr/local/bin:/usr/bin:/bin:/usr/games:{}/.local/bin", home))
+        );
+        assert_eq!(get("QUOTED").as_deref(), Some("with spaces"));
+        assert_eq!(get("ESCAPED").as_deref(), Some("${NOT_EXPANDED}"));
+        assert_eq!(get("SIMPLE").as_deref(), Some("plain"));
+        assert_eq!(get("TZ"), None);
+        assert_eq!(get("invalid-key"), None);
+        // 7. Variables describing the session win over everything
+        assert_eq!(get("HOME"), Some(home));
+        assert_eq!(get("USER").as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn config_variables_are_expanded_in_file_order() {
+        let config: Config = toml::from_str("[environment]\nVISUAL = \"nano\"\nEDITOR = \"${VISUAL}\"\n").unwrap();
+        let env = variables(&sample_user(), &sway(), "C.UTF-8", &config, &no_sources(), &daemon_env);
+        assert_eq!(env.get("VISUAL"), Some(&OsString::from("nano")));
+        assert_eq!(env.get("EDITOR"), Some(&OsString::from("nano")));
+    }
+
+    #[test]
+    fn other_locale_drops_system_locale_categories() {
+        let env = variables(
+            &fixture_user(),
+            &sway(),
+            "de_DE.UTF-8",
+            &fixture_config(),
+            &fixture_sources(),
+            &daemon_env,
+        );
+
+        assert_eq!(env["LANG"], "de_DE.UTF-8");
+        assert!(!env.contains_key("LC_TIME"));
+        assert!(!env.contains_key("LC_PAPER"));
+        assert!(!env.contains_key("LANGUAGE"));
+    }
+
+    #[test]
+    fn expand_values() {
+        let user = sample_user();
+        let env = HashMap::from([("A".to_owned(), OsString::from("a"))]);
+
+        assert_eq!(
+            expand("${A}-${B}-@{HOME}-@{SHELL}-@{OTHER}", &env, &user),
+            "a--/home/alice-/bin/zsh-"
+        );
+        assert_eq!(
+            expand("\\${A} \\@{HOME} \\ \n $A", &env, &user),
+            "${A} @{HOME} \\ \n $A"
+        );
+    }
+
+    #[test]
+    fn split_pam_environment_words() {
+        assert_eq!(
+            split_words(r#"KEY  DEFAULT="a b"   OVERRIDE=c"#),
+            vec!["KEY", "DEFAULT=a b", "OVERRIDE=c"]
+        );
+        assert_eq!(unquote("'single'"), "single");
+        assert_eq!(unquote("\"double\""), "double");
+        assert_eq!(unquote("\"mismatched'"), "\"mismatched'");
+    }
+
+    #[test]
+    fn login_environment_of_a_session() {
+        let env = variables(
+            &sample_user(),
+            &sway(),
+            "C.UTF-8",
+            &Config::default(),
+            &no_sources(),
+            &daemon_env,
+        );
+
+        let expected = [
+            ("TERM", "vt220"),
+            ("PATH", "/usr/local/bin:/usr/bin:/bin"),
+            ("MAIL", "/var/mail/alice"),
+            ("LANG", "C.UTF-8"),
+            ("HOME", "/home/alice"),
+            ("PWD", "/home/alice"),
+            ("USER", "alice"),
+            ("LOGNAME", "alice"),
+            ("SHELL", "/bin/zsh"),
+            ("XDG_CONFIG_HOME", "/home/alice/.config"),
+            ("XDG_DATA_HOME", "/home/alice/.local/share"),
+            ("XDG_STATE_HOME", "/home/alice/.local/state"),
+            ("XDG_CACHE_HOME", "/home/alice/.cache"),
+            ("XDG_CONFIG_DIRS", "/etc/xdg"),
+            ("XDG_DATA_DIRS", "/usr/local/share:/usr/share"),
+            ("XDG_RUNTIME_DIR", "/run/user/1000"),
+            ("XDG_SEAT", "seat0"),
+            ("XDG_VTNR", "7"),
+            ("XDG_SESSION_CLASS", "user"),
+            ("XDG_SESSION_TYPE", "wayland"),
+            ("XDG_SESSION_DESKTOP", "sway"),
+            ("DESKTOP_SESSION", "sway"),
+            ("XDG_CURRENT_DESKTOP", "sway:wlroots"),
+        ];
+        let expected = expected
+            .iter()
+            .map(|(key, value)| (key.to_string(), OsString::from(value)))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(env, expected);
+    }
+
+    #[test]
+    fn root_gets_the_sbin_directories() {
+        let root = User::sample(0, "root", "/root", "/bin/bash");
+        let env = variables(
+            &root,
+            &sway(),
+            "C.UTF-8",
+            &Config::default(),
+            &no_sources(),
+            &daemon_env,
+        );
+
+        assert_eq!(
+            env["PATH"],
+            "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin"
+        );
+        assert_eq!(env["MAIL"], "/var/mail/root");
+        assert_eq!(env["XDG_RUNTIME_DIR"], "/run/user/0");
+    }
+
+    #[test]
+    fn session_id_from_pam_is_kept() {
+        let mut env = Env {
+            runtime_dir: "/run/user/1000".to_owned(),
+            variables: HashMap::from([("XDG_SESSION_ID".to_owned(), "c2".into())]),
+        };
+        env.set_session_id();
+        assert_eq!(env.variables["XDG_SESSION_ID"], "c2");
+    }
+
+    #[test]
+    fn commands_get_nothing_but_the_session_environment() {
+        // The test process itself has variables like PATH and HOME which must not be inherited
+        let env = Env {
+            runtime_dir: "/run/user/1000".to_owned(),
+            variables: HashMap::from([("SESSION_ONLY".to_owned(), "1".into())]),
+        };
+        let mut command = Command::new("/usr/bin/env");
+        env.apply(&mut command);
+
+        let output = command.output().unwrap();
+        assert!(output.status.success());
+        assert_eq!(String::from_utf8(output.stdout).unwrap(), "SESSION_ONLY=1\n");
+    }
+}
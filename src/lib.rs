@@ -1,43 +1,292 @@
-use std::collections::HashMap;
-use std::ffi::{OsStr, OsString};
-use std::os::unix::process::CommandExt;
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
 use std::path::Path;
-use std::process::{Child, Command};
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
+use dialoguer::console::style;
 use dialoguer::console::Term;
-use dialoguer::theme::Theme;
-use dialoguer::{Password, Select};
+use dialoguer::theme::{ColorfulTheme, SimpleTheme, Theme};
+use dialoguer::{Input, Password, Select};
 use signal_hook::consts::signal::*;
-use signal_hook::iterator::Signals;
 use zeroize::Zeroizing;
 
-pub use self::config::{Config, LaunchType};
-use self::desktop::Desktop;
+pub use self::config::{AuthBackend, AutoLoginMode, Config, LaunchType, ThemeKind, DEFAULT_CONFIG_PATH};
+use self::desktop::{Desktop, SessionType};
+use self::env::Env;
+use self::pam::{Conversation, Pam};
+use self::process::{Event, SessionGroup, SignalFd, Termination};
 use self::user::User;
-use self::xdisplay::XDisplay;
+use self::xauth::{Cookie, XAuthority};
+use self::xdisplay::{DisplayFd, ReadySignal, XDisplay};
 
 mod config;
 mod desktop;
+mod env;
+mod locale;
+mod pam;
+mod process;
+mod terminal;
+#[cfg(test)]
+mod testing;
 mod user;
+mod xauth;
 mod xdisplay;
 
+const RUNTIME_DIR: &str = "/run/dsdmona";
+const AUTO_LOGIN_MARKER_PATH: &str = "/run/dsdmona/auto-login";
+/// How long errors stay on screen before the greeter is shown again.
+const ERROR_DELAY: Duration = Duration::from_secs(10);
+/// Signals which stop dsdmona, like `systemctl stop` does. Keys typed on the VT don't, so that
+/// Ctrl+C or Ctrl+\ in the greeter can't take down the display manager.
+const TERMINATION_SIGNALS: [libc::c_int; 2] = [SIGHUP, SIGTERM];
+
+/// Runs the greeter until a termination signal is received.
+///
+/// A signal received while the greeter is shown exits immediately, while a signal received
+/// during a session ends the session first.
 pub fn login(config: Config) -> Result<()> {
-    let theme = dialoguer::theme::ColorfulTheme::default();
+    let theme: Box<dyn Theme> = match config.theme {
+        ThemeKind::Colorful => Box::new(ColorfulTheme::default()),
+        ThemeKind::Simple => Box::new(SimpleTheme),
+    };
     let term = Term::stdout();
+    let vt_state = terminal::VtState::save();
+
+    let greeter_active = Arc::new(AtomicBool::new(true));
+    let shutdown = Arc::new(AtomicBool::new(false));
+    for signal in TERMINATION_SIGNALS {
+        signal_hook::flag::register_conditional_shutdown(signal, 0, greeter_active.clone())?;
+        signal_hook::flag::register(signal, shutdown.clone())?;
+    }
+    // Handled instead of ignored, so that sessions still start with the default disposition
+    let interrupted = Arc::new(AtomicBool::new(false));
+    for signal in [SIGINT, SIGQUIT] {
+        signal_hook::flag::register(signal, interrupted.clone())?;
+    }
+    let mut auto_login_attempted = false;
+
+    while !shutdown.load(Ordering::Acquire) {
+        vt_state.restore();
+        term.clear_screen()?;
+        term.show_cursor()?;
+        greeter_active.store(true, Ordering::Release);
+
+        if let Err(e) = greet(
+            theme.as_ref(),
+            &term,
+            &config,
+            &greeter_active,
+            &mut auto_login_attempted,
+        ) {
+            term.show_cursor()?;
+            eprintln!("{:#}", e);
+            terminal::wait_key(ERROR_DELAY)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Selects a user and a desktop and runs a single session.
+fn greet(
+    theme: &dyn Theme,
+    term: &Term,
+    config: &Config,
+    greeter_active: &AtomicBool,
+    auto_login_attempted: &mut bool,
+) -> Result<()> {
+    let (user, pam, auto_login) = match auto_login(theme, term, config, auto_login_attempted)? {
+        Some((user, pam)) => (user, pam, true),
+        None => {
+            let (user, pam) = select_user(theme, term, config)?;
+            (user, pam, false)
+        }
+    };
+    let locale = select_locale(theme, term, &user, config, auto_login)?;
+    let desktop = select_desktop(theme, term, &user, &locale, config, auto_login)?;
+    greeter_active.store(false, Ordering::Release);
+
+    // The session is opened by a worker process instead of dsdmona, so that the worker becomes
+    // the leader of the logind session. Termination signals stay blocked in both processes.
+    let signals = SignalFd::block(&TERMINATION_SIGNALS)?;
+    let service_cgroup = process::own_cgroup().ok();
+    std::io::stdout().flush()?;
+    match unsafe { libc::fork() } {
+        -1 => anyhow::bail!("Failed to fork session worker: {}", std::io::Error::last_os_error()),
+        0 => {
+            let code = match run_session(
+                &user,
+                &desktop,
+                &locale,
+                pam,
+                service_cgroup.as_deref(),
+                &signals,
+                config,
+            ) {
+                Ok(()) => 0,
+                Err(e) => {
+                    eprintln!("{:#}", e);
+                    1
+                }
+            };
+            std::process::exit(code);
+        }
+        worker => {
+            if let Some(pam) = pam {
+                pam.end_after_fork();
+            }
+            wait_worker(worker, &signals)
+        }
+    }
+}
+
+/// Opens the PAM session, runs the desktop and closes the session again.
+///
+/// Runs in the session worker.
+fn run_session(
+    user: &User,
+    desktop: &Desktop,
+    locale: &str,
+    mut pam: Option<Pam>,
+    service_cgroup: Option<&Path>,
+    signals: &SignalFd,
+    config: &Config,
+) -> Result<()> {
+    let mut env = Env::define(user, desktop, locale, config)?;
+
+    if let Some(pam) = &mut pam {
+        for key in [
+            "XDG_SEAT",
+            "XDG_VTNR",
+            "XDG_SESSION_CLASS",
+            "XDG_SESSION_TYPE",
+            "XDG_SESSION_DESKTOP",
+        ] {
+            pam.putenv(key, &env.variables[key])?;
+        }
+        pam.open_session()?;
+
+        env.variables.extend(pam.env_list());
+    }
+    env.set_session_id();
+
+    run_hook(&config.hooks.pre_session, user, desktop, config)?;
 
-    let user = select_user(&theme, &term, &config)?;
-    let desktop = select_desktop(&theme, &term, &user, &config)?;
-    let env = Env::define(&user, &desktop)?;
+    let result = {
+        // Tracked through the scope of the logind session if PAM opened one
+        let mut session = SessionGroup::new(service_cgroup, &session_cgroup_name(config));
+        match desktop.session_type {
+            SessionType::X11 => xorg(user, desktop, env, &mut session, signals, config),
+            SessionType::Wayland => wayland(user, desktop, env, &mut session, signals, config),
+        }
+    };
+
+    if let Err(e) = run_hook(&config.hooks.post_session, user, desktop, config) {
+        eprintln!("Post session hook failed: {}", e);
+    }
+
+    if let Some(mut pam) = pam {
+        pam.close_session()?;
+    }
 
-    xorg(user, desktop, env, config)?;
+    result
+}
+
+/// Waits for the session worker, asking it to end the session on a termination signal.
+fn wait_worker(worker: libc::pid_t, signals: &SignalFd) -> Result<()> {
+    if let Event::Signal = process::wait_any(&[worker], Some(signals), None)? {
+        unsafe { libc::kill(worker, libc::SIGTERM) };
+        process::wait_any(&[worker], None, None)?;
+    }
+
+    let mut status = 0;
+    while unsafe { libc::waitpid(worker, &mut status, 0) } < 0 {
+        let e = std::io::Error::last_os_error();
+        anyhow::ensure!(
+            e.kind() == std::io::ErrorKind::Interrupted,
+            "Failed to wait for session worker: {}",
+            e
+        );
+    }
 
+    let status = ExitStatus::from_raw(status);
+    anyhow::ensure!(status.success(), "Session worker failed with {}", status);
     Ok(())
 }
 
-pub fn select_user(theme: &dyn Theme, term: &Term, _config: &Config) -> Result<User> {
-    let users = User::all();
+/// Logs in the configured auto-login user without asking for a password.
+///
+/// Returns `None` if auto-login is disabled, was already attempted since dsdmona started (or since
+/// boot in `first-boot` mode) or was cancelled.
+pub fn auto_login<'a>(
+    theme: &'a dyn Theme,
+    term: &'a Term,
+    config: &Config,
+    attempted: &mut bool,
+) -> Result<Option<(User, Option<Pam<'a>>)>> {
+    let Some(name) = &config.auto_login.user else {
+        return Ok(None);
+    };
+
+    // Set before trying, so that logging out or a failure shows the greeter instead of a loop
+    if std::mem::replace(attempted, true) {
+        return Ok(None);
+    }
+
+    let marker = Path::new(AUTO_LOGIN_MARKER_PATH);
+    if config.auto_login.mode == AutoLoginMode::FirstBoot {
+        if marker.exists() {
+            return Ok(None);
+        }
+
+        if let Some(parent) = marker.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(marker, "")?;
+    }
+
+    let user = User::from_name(name)?;
+
+    for remaining in (1..=config.auto_login.countdown).rev() {
+        term.clear_line()?;
+        term.write_str(&format!(
+            "Logging in as {} in {}s. Press any key to cancel.",
+            name, remaining
+        ))?;
+
+        if terminal::wait_key(Duration::from_secs(1))? {
+            term.clear_line()?;
+            return Ok(None);
+        }
+    }
+    term.clear_line()?;
+
+    let pam = match config.auth.backend {
+        AuthBackend::Pam => {
+            let conversation = TermConversation { theme, term };
+            let mut pam = Pam::start(&config.auth.autologin_service, user.name(), Box::new(conversation))?;
+            pam.set_tty(&format!("tty{}", config.tty))?;
+
+            anyhow::ensure!(pam.authenticate()?, "Auto-login was rejected by PAM");
+            pam.acct_mgmt()?;
+            Some(pam)
+        }
+        AuthBackend::Shadow => None,
+    };
+
+    Ok(Some((user, pam)))
+}
+
+pub fn select_user<'a>(theme: &'a dyn Theme, term: &'a Term, config: &Config) -> Result<(User, Option<Pam<'a>>)> {
+    let users = User::all(config.users.min_uid, config.users.max_uid);
     anyhow::ensure!(!users.is_empty(), "No users found");
 
     let mut selection = Select::with_theme(theme);
@@ -48,88 +297,191 @@ pub fn select_user(theme: &dyn Theme, term: &Term, _config: &Config) -> Result<U
     let user = selection.default(0).with_prompt("Select user:").interact_on(term)?;
     let user = users[user].clone();
 
-    loop {
-        let password = Password::with_theme(theme)
-            .with_prompt("Enter password:")
-            .interact_on(term)
+    match config.auth.backend {
+        AuthBackend::Pam => {
+            let conversation = TermConversation { theme, term };
+            let mut pam = Pam::start(&config.auth.service, user.name(), Box::new(conversation))?;
+            pam.set_tty(&format!("tty{}", config.tty))?;
+
+            loop {
+                if pam.authenticate()? {
+                    pam.acct_mgmt()?;
+                    break Ok((user, Some(pam)));
+                } else {
+                    println!("Invalid password!");
+                }
+            }
+        }
+        AuthBackend::Shadow => loop {
+            let password = Password::with_theme(theme)
+                .with_prompt("Enter password:")
+                .interact_on(term)
+                .map(Zeroizing::new)?;
+
+            if user.check_password(&password)? {
+                break Ok((user, None));
+            } else {
+                println!("Invalid password!");
+            }
+        },
+    }
+}
+
+struct TermConversation<'a> {
+    theme: &'a dyn Theme,
+    term: &'a Term,
+}
+
+impl Conversation for TermConversation<'_> {
+    fn prompt_echo_off(&mut self, message: &str) -> Result<Zeroizing<String>> {
+        let password = Password::with_theme(self.theme)
+            .with_prompt(prompt_text(message))
+            .allow_empty_password(true)
+            .interact_on(self.term)
             .map(Zeroizing::new)?;
+        Ok(password)
+    }
+
+    fn prompt_echo_on(&mut self, message: &str) -> Result<String> {
+        let input = Input::<String>::with_theme(self.theme)
+            .with_prompt(prompt_text(message))
+            .allow_empty(true)
+            .interact_text_on(self.term)?;
+        Ok(input)
+    }
 
-        if user.check_password(&password)? {
-            break Ok(user);
-        } else {
-            println!("Invalid password!");
+    fn info(&mut self, message: &str) {
+        if let Err(e) = self.term.write_line(message) {
+            eprintln!("Failed to write PAM message: {}", e);
         }
     }
+
+    fn error(&mut self, message: &str) {
+        if let Err(e) = self.term.write_line(&style(message).red().to_string()) {
+            eprintln!("Failed to write PAM message: {}", e);
+        }
+    }
+}
+
+/// PAM prompts usually end with `": "` which themes already add.
+fn prompt_text(message: &str) -> &str {
+    message.trim_end().trim_end_matches(':')
 }
 
-pub fn select_desktop(theme: &dyn Theme, term: &Term, user: &User, config: &Config) -> Result<Desktop> {
-    let desktops = Desktop::all();
+/// Asks for the session language unless disabled or there is nothing to choose from.
+fn select_locale(theme: &dyn Theme, term: &Term, user: &User, config: &Config, auto_login: bool) -> Result<String> {
+    let last_locale = user.get_last_locale();
+    let default_locale = last_locale
+        .clone()
+        .or_else(locale::system)
+        .unwrap_or_else(|| locale::DEFAULT_LOCALE.to_owned());
+
+    let locales = locale::available();
+    if auto_login || !config.select_locale || locales.len() < 2 {
+        return Ok(default_locale);
+    }
+
+    let default = locales
+        .iter()
+        .position(|item| locale::is_same(item, &default_locale))
+        .unwrap_or_default();
+    let selection = Select::with_theme(theme)
+        .items(&locales)
+        .default(default)
+        .with_prompt("Select language:")
+        .interact_on(term)?;
+    let locale = locales[selection].clone();
+
+    if !last_locale.is_some_and(|last_locale| locale::is_same(&last_locale, &locale)) {
+        if let Err(e) = user.set_last_locale(&locale) {
+            eprintln!("Failed to set last locale: {}", e);
+        }
+    }
+
+    Ok(locale)
+}
+
+pub fn select_desktop(
+    theme: &dyn Theme,
+    term: &Term,
+    user: &User,
+    locale: &str,
+    config: &Config,
+    auto_login: bool,
+) -> Result<Desktop> {
+    let mut data_dirs = config.sessions.data_dirs();
+    if config.sessions.user_sessions {
+        data_dirs.insert(0, user.home_dir().join(".local/share"));
+    }
+
+    let desktops = {
+        let _fs = user.fs_guard()?;
+        let mut desktops = Desktop::all(&data_dirs, Some(locale));
+        desktops.extend(Desktop::user_xsession(user.home_dir()));
+        desktops
+    };
     anyhow::ensure!(!desktops.is_empty(), "No desktops found");
 
     let last_desktop = user.get_last_desktop(&desktops);
-    if let Some(auto_login_session) = &config.auto_login_session {
-        let auto_login_session = auto_login_session.trim();
-        if let Some(desktop) = desktops
-            .iter()
-            .find(|desktop| desktop.exec.starts_with(auto_login_session))
-        {
-            update_last_session(user, last_desktop, desktop);
-            return Ok(desktop.clone());
-        };
+    if auto_login {
+        match &config.auto_login.session {
+            Some(auto_login_session) => {
+                if let Some(desktop) = find_session(&desktops, auto_login_session) {
+                    update_last_session(user, last_desktop, desktop);
+                    return Ok(desktop.clone());
+                };
+            }
+            None => {
+                if let Some(desktop) = last_desktop {
+                    return Ok(desktop.clone());
+                }
+            }
+        }
     }
 
+    let default_desktop = last_desktop.or_else(|| find_session(&desktops, config.default_session.as_ref()?));
+    let default = default_desktop
+        .and_then(|default| desktops.iter().position(|desktop| desktop.is_same_session(default)))
+        .unwrap_or_default();
+
     let mut selection = Select::with_theme(theme);
     for desktop in &desktops {
-        selection.item(&desktop.name);
+        match desktop.session_type {
+            SessionType::X11 => selection.item(&desktop.name),
+            SessionType::Wayland => selection.item(format!("{} (Wayland)", desktop.name)),
+        };
     }
 
-    let selection = selection.default(0).with_prompt("Select desktop:").interact_on(term)?;
+    let selection = selection
+        .default(default)
+        .with_prompt("Select desktop:")
+        .interact_on(term)?;
+    update_last_session(user, last_desktop, &desktops[selection]);
     Ok(desktops[selection].clone())
 }
 
-struct Env {
-    runtime_dir: String,
-    variables: HashMap<&'static str, OsString>,
-}
-
-impl Env {
-    fn define(user: &User, desktop: &Desktop) -> Result<Self> {
-        let runtime_dir = format!("/run/user/{}", user.uid());
-
-        let mut env: HashMap<&'static str, OsString> = HashMap::new();
-
-        env.insert("HOME", user.home_dir().into());
-        env.insert("PWD", user.home_dir().into());
-        env.insert("USER", user.name().into());
-        env.insert("LOGNAME", user.name().into());
-        env.insert("XDG_CONFIG_HOME", user.home_dir().join(".config").into());
-        env.insert("XDG_RUNTIME_DIR", runtime_dir.clone().into());
-        env.insert("XDG_SEAT", "seat0".into());
-        env.insert("XDG_SESSION_CLASS", "user".into());
-        env.insert("SHELL", user.shell().into());
-        env.insert("LANG", "en_US.UTF-8".into());
-        env.insert("PATH", std::env::var("PATH")?.into());
-
-        env.insert("DESKTOP_SESSION", desktop.name.clone().into());
-        env.insert("XDG_SESSION_DESKTOP", desktop.name.clone().into());
-
-        if !Path::new(&runtime_dir).exists() {
-            std::fs::create_dir_all(&runtime_dir)?;
-            user.set_file_owner(&runtime_dir)?;
-        }
-
-        std::env::set_current_dir(user.home_dir())?;
+/// Finds a configured session by desktop file ID, optionally prefixed with `x11:` or `wayland:`.
+///
+/// Values which name no ID are matched against the beginning of `Exec`, like older configs did.
+fn find_session<'a>(desktops: &'a [Desktop], session: &str) -> Option<&'a Desktop> {
+    let session = session.trim();
+    let (session_type, id) = match session.split_once(':') {
+        Some(("x11", id)) => (Some(SessionType::X11), id),
+        Some(("wayland", id)) => (Some(SessionType::Wayland), id),
+        _ => (None, session),
+    };
 
-        Ok(Self {
-            runtime_dir,
-            variables: env,
+    desktops
+        .iter()
+        .find(|desktop| {
+            desktop.id == id && session_type.is_none_or(|session_type| desktop.session_type == session_type)
         })
-    }
+        .or_else(|| desktops.iter().find(|desktop| desktop.exec.starts_with(session)))
 }
 
 fn update_last_session(user: &User, last_desktop: Option<&Desktop>, desktop: &Desktop) {
     let last_session_changed = match last_desktop {
-        Some(last_desktop) => last_desktop.exec != desktop.exec,
+        Some(last_desktop) => !last_desktop.is_same_session(desktop),
         None => true,
     };
 
@@ -140,112 +492,311 @@ fn update_last_session(user: &User, last_desktop: Option<&Desktop>, desktop: &De
     }
 }
 
-fn xorg(user: User, desktop: Desktop, mut env: Env, config: Config) -> Result<()> {
-    let Some(free_display) = XDisplay::find_free_xdisplay() else {
-        anyhow::bail!("There is no free xdisplay");
+fn run_hook(hook: &[String], user: &User, desktop: &Desktop, config: &Config) -> Result<()> {
+    let Some((bin, args)) = hook.split_first() else {
+        return Ok(());
     };
 
-    let xauthority = Path::new(&env.runtime_dir).join(".Xauthority");
-    let display = format!(":{free_display}");
+    let status = Command::new(bin)
+        .args(args)
+        .env("DSDMONA_USER", user.name())
+        .env("DSDMONA_SESSION", &desktop.exec)
+        .env("DSDMONA_TTY", config.tty.to_string())
+        .status()?;
+    anyhow::ensure!(status.success(), "Hook `{}` failed with {}", bin, status);
 
-    env.variables.insert("XDG_SESSION_TYPE", "x11".into());
-    env.variables.insert("XAUTHORITY", xauthority.as_os_str().to_owned());
-    env.variables.insert("DISPLAY", display.clone().into());
+    Ok(())
+}
 
-    std::env::set_var("XAUTHORITY", &xauthority);
-    std::env::set_var("DISPLAY", &display);
+fn xorg(
+    user: &User,
+    desktop: &Desktop,
+    mut env: Env,
+    session: &mut SessionGroup,
+    signals: &SignalFd,
+    config: &Config,
+) -> Result<()> {
+    let xauthority = Path::new(&env.runtime_dir).join(".Xauthority");
+    let cookie = Cookie::generate()?;
 
-    std::fs::write(&xauthority, "")?;
-    user.set_file_owner(&xauthority)?;
+    let server_xauthority = Path::new(RUNTIME_DIR).join(format!("vt{}.Xauthority", config.tty));
+    std::fs::create_dir_all(RUNTIME_DIR)?;
+    XAuthority::for_server(&cookie).write(&server_xauthority)?;
 
-    // Generate mcookie
-    let output = exec_cmd("/usr/bin/mcookie", &user, &env).output()?;
-    let mcookie = String::from_utf8(output.stdout)?;
+    let log_file = &config.xserver.log_file;
+    if let Some(parent) = log_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let log = std::fs::File::create(log_file)?;
 
-    // Generate xauth
-    let _output = exec_cmd("/usr/bin/xauth", &user, &env)
-        .arg("add")
-        .arg(&display)
-        .arg(".")
-        .arg(mcookie.trim())
-        .output()?;
+    let xserverrc = user.home_dir().join(".xserverrc");
+    let has_xserverrc = {
+        let _fs = user.fs_guard()?;
+        xserverrc.is_file()
+    };
+    // A server started by ~/.xserverrc runs as the user and can't signal root
+    let rootless = config.xserver.user_xserverrc && has_xserverrc;
+    let mut xorg = if rootless {
+        user.set_file_owner(&server_xauthority)?;
+
+        let mut command = exec_cmd("/bin/sh", user, &env, None)?;
+        command.arg(xserverrc);
+        command
+    } else {
+        let mut command = Command::new(&config.xserver.path);
+        command.env_clear().envs(env::passed_through(config));
+        command
+    };
 
     // Start XOrg
-    let xorg = Command::new("/usr/bin/Xorg")
-        .arg(format!("vt{}", config.tty))
-        .arg(&display)
-        .envs(std::env::vars())
-        .spawn()?;
+    let ready = if rootless { None } else { Some(ReadySignal::register()?) };
+    let display_fd = DisplayFd::new()?;
+    if config.xserver.vt {
+        xorg.arg(format!("vt{}", config.tty));
+    }
+    xorg.arg("-auth").arg(&server_xauthority).stderr(log);
+    display_fd.pass_to(&mut xorg);
+    if let Some(ready) = &ready {
+        ready.request_from(&mut xorg);
+    }
+    if !config.xserver.listen_tcp {
+        xorg.args(["-nolisten", "tcp"]);
+    }
+    if config.xserver.keeptty {
+        xorg.arg("-keeptty");
+    }
+    if config.xserver.novtswitch {
+        xorg.arg("-novtswitch");
+    }
+    let mut xorg = match xorg.args(&config.xserver.args).args(&desktop.server_args).spawn() {
+        Ok(xorg) => xorg,
+        Err(e) => {
+            std::fs::remove_file(&server_xauthority)?;
+            return Err(e.into());
+        }
+    };
     println!("Started Xorg");
 
     let start_xinit = || -> Result<(XDisplay, Child)> {
-        let display = XDisplay::open(&display)?;
+        let timeout = Duration::from_secs(config.xserver.startup_timeout);
+        let started = match &ready {
+            Some(ready) => ready.wait(&mut xorg, timeout).and_then(|()| display_fd.read(timeout)),
+            None => display_fd.read(timeout),
+        };
+        let display_number = match started {
+            Ok(display_number) => display_number,
+            Err(e) => anyhow::bail!(
+                "{}. Last lines of {}:\n{}",
+                e,
+                log_file.display(),
+                log_tail(log_file, 20)
+            ),
+        };
+        let display = format!(":{display_number}");
+
+        env.variables
+            .insert("XAUTHORITY".into(), xauthority.as_os_str().to_owned());
+        env.variables.insert("DISPLAY".into(), display.clone().into());
+
+        std::env::set_var("XAUTHORITY", &xauthority);
+        std::env::set_var("DISPLAY", &display);
+
+        // Generate xauth. Written as the user, the runtime directory belongs to them
+        {
+            let _fs = user.fs_guard()?;
+            XAuthority::for_display(&cookie, display_number)?.write(&xauthority)?;
+        }
+
+        let xdisplay = XDisplay::open(&display)?;
 
         // Start xinit
-        let xinit = prepare_gui_command(&user, &desktop, &env, &config)?
-            .current_dir(user.home_dir())
-            .spawn()?;
+        let xinit = session.spawn(&mut prepare_gui_command(user, desktop, &env, session, config)?)?;
         println!("Started XInit");
 
-        Ok((display, xinit))
+        Ok((xdisplay, xinit))
     };
-    let (_xdisplay, xinit) = match start_xinit() {
-        Ok(r) => r,
-        Err(e) => {
-            kill_process(xorg.id());
-            wait_process(xorg);
-            return Err(e);
+    let result = start_xinit().map(|(xdisplay, xinit)| {
+        let end = wait_session(xinit, Some(&xorg), session, signals, config);
+        println!("XInit finished");
+        match end {
+            // Closing the connection to a dead server would end up in Xlib's fatal IO error handler
+            SessionEnd::ServerExited => xdisplay.abandon(),
+            _ => drop(xdisplay),
         }
-    };
+        end
+    });
 
-    let mut signals = Signals::new([SIGHUP, SIGINT, SIGQUIT, SIGTERM]).unwrap();
-    let signals_handle = signals.handle();
-    std::thread::spawn({
-        let xinit_id = xinit.id();
-        move || {
-            for _ in signals.forever() {
-                kill_process(xinit_id);
-            }
+    let termination = stop_process(&mut xorg, "Xorg", config);
+
+    // Remove auth
+    std::fs::remove_file(&server_xauthority)?;
+    {
+        let _fs = user.fs_guard()?;
+        if xauthority.exists() {
+            std::fs::remove_file(&xauthority)?;
         }
-    });
+    }
 
-    loop {
-        let r = unsafe { libc::getpgid(xinit.id() as i32) };
-        if r < 0 {
-            break;
-        } else {
-            std::thread::sleep(Duration::from_millis(500));
+    match (result?, termination) {
+        (SessionEnd::ServerExited, Some(termination)) => anyhow::bail!(
+            "X server {} during the session. Last lines of {}:\n{}",
+            termination,
+            log_file.display(),
+            log_tail(log_file, 20)
+        ),
+        _ => Ok(()),
+    }
+}
+
+fn log_tail(path: &Path, lines: usize) -> String {
+    match std::fs::read_to_string(path) {
+        Ok(log) => {
+            let log = log.lines().collect::<Vec<_>>();
+            log[log.len().saturating_sub(lines)..].join("\n")
         }
+        Err(e) => format!("Failed to read log: {}", e),
     }
+}
 
-    wait_process(xinit);
-    println!("XInit finished");
+fn wayland(
+    user: &User,
+    desktop: &Desktop,
+    env: Env,
+    session: &mut SessionGroup,
+    signals: &SignalFd,
+    config: &Config,
+) -> Result<()> {
+    let tty_path = format!("/dev/tty{}", config.tty);
+    let tty = OpenOptions::new().read(true).write(true).open(&tty_path)?;
+
+    // Let the compositor use its VT
+    let tty_metadata = tty.metadata()?;
+    user.set_file_owner(&tty_path)?;
+
+    let mut start_compositor = || -> Result<Child> {
+        let mut command = prepare_gui_command(user, desktop, &env, session, config)?;
+        command
+            .stdin(tty.try_clone()?)
+            .stdout(tty.try_clone()?)
+            .stderr(tty.try_clone()?);
+
+        session.spawn(&mut command)
+    };
+    let result = start_compositor().map(|compositor| {
+        println!("Started Wayland compositor");
+        wait_session(compositor, None, session, signals, config);
+        println!("Wayland compositor finished");
+    });
 
-    signals_handle.close();
+    std::os::unix::fs::chown(&tty_path, Some(tty_metadata.uid()), Some(tty_metadata.gid()))?;
 
-    kill_process(xorg.id());
-    wait_process(xorg);
-    println!("XOrg finished");
+    result
+}
 
-    // Remove auth
-    std::fs::remove_file(&xauthority)?;
+/// Waits for the session to exit, terminating it when its server exits or on SIGHUP or SIGTERM.
+///
+/// Processes left behind by the session are terminated afterwards.
+fn wait_session(
+    mut session: Child,
+    server: Option<&Child>,
+    group: &SessionGroup,
+    signals: &SignalFd,
+    config: &Config,
+) -> SessionEnd {
+    let pids = std::iter::once(&session)
+        .chain(server)
+        .map(|child| child.id() as libc::pid_t)
+        .collect::<Vec<_>>();
+    let event = process::wait_any(&pids, Some(signals), None);
+
+    let end = match event {
+        Ok(Event::Exited(0)) => {
+            match session.wait() {
+                Ok(status) => println!("Session exited with {}", status),
+                Err(e) => eprintln!("Failed to wait child process: {}", e),
+            }
+            SessionEnd::Exited
+        }
+        Ok(Event::Exited(_)) => {
+            eprintln!("X server exited, stopping the session");
+            stop_process(&mut session, "Session", config);
+            SessionEnd::ServerExited
+        }
+        Ok(_) => {
+            stop_process(&mut session, "Session", config);
+            SessionEnd::Stopped
+        }
+        Err(e) => {
+            eprintln!("Failed to wait for the session: {}", e);
+            stop_process(&mut session, "Session", config);
+            SessionEnd::Stopped
+        }
+    };
 
-    Ok(())
+    group.terminate(Duration::from_secs(config.termination_timeout));
+    end
+}
+
+/// Why [`wait_session`] returned.
+#[derive(Copy, Clone, Eq, PartialEq)]
+enum SessionEnd {
+    Exited,
+    ServerExited,
+    Stopped,
+}
+
+fn session_cgroup_name(config: &Config) -> String {
+    format!("session-vt{}", config.tty)
 }
 
-fn prepare_gui_command(user: &User, desktop: &Desktop, env: &Env, config: &Config) -> Result<Command> {
-    let exec = desktop.exec.split(' ').map(OsString::from).collect::<Vec<_>>();
+fn prepare_gui_command(
+    user: &User,
+    desktop: &Desktop,
+    env: &Env,
+    session: &SessionGroup,
+    config: &Config,
+) -> Result<Command> {
+    let exec = desktop.argv.iter().map(OsString::from).collect::<Vec<_>>();
+
+    let xinitrc = user.home_dir().join(".xinitrc");
+    let xsession = user.home_dir().join(desktop::USER_XSESSION_PATH);
+    let (has_xinitrc, has_xsession) = {
+        let _fs = user.fs_guard()?;
+        (xinitrc.exists(), xsession.exists())
+    };
 
-    let (bin, args): (OsString, Vec<OsString>) = if config.launch_type == LaunchType::XInitRc
+    let (bin, args): (OsString, Vec<OsString>) = if desktop.session_type == SessionType::X11
+        && config.launch_type == LaunchType::XInitRc
         && !exec.contains(&OsString::from(".xinitrc"))
-        && user.home_dir().join(".xinitrc").exists()
+        && has_xinitrc
+    {
+        // Like xinit, which runs the script with sh
+        let mut argv = vec!["/bin/sh".into(), xinitrc.into_os_string()];
+        argv.extend(exec);
+        login_shell(user, &argv)
+    } else if desktop.session_type == SessionType::X11
+        && config.launch_type == LaunchType::UserXSession
+        && desktop.path != xsession
+        && has_xsession
     {
-        let bin = "/bin/bash".into();
-        let mut args = vec!["--login".into(), user.home_dir().join(".xinitrc").into_os_string()];
-        args.extend(exec.into_iter());
-        (bin, args)
+        // Run with sh like ~/.xinitrc, so that it doesn't need to be executable
+        let mut argv = vec!["/bin/sh".into(), xsession.into_os_string()];
+        argv.extend(exec);
+        login_shell(user, &argv)
+    } else if desktop.session_type == SessionType::X11 && config.launch_type == LaunchType::XSession {
+        anyhow::ensure!(!exec.is_empty(), "Empty exec");
+        (
+            config.sessions.xsession_script.clone().into_os_string(),
+            vec![xsession_command_line(&exec)],
+        )
     } else if config.launch_type == LaunchType::DBus {
-        ("dbus-launch".into(), exec)
+        match desktop.session_type {
+            SessionType::X11 => ("dbus-launch".into(), exec),
+            SessionType::Wayland => ("dbus-run-session".into(), exec),
+        }
+    } else if config.launch_type == LaunchType::LoginShell {
+        anyhow::ensure!(!exec.is_empty(), "Empty exec");
+        login_shell(user, &exec)
     } else {
         let Some((bin, args)) = exec.split_first() else {
             anyhow::bail!("Empty exec");
@@ -253,30 +804,170 @@ fn prepare_gui_command(user: &User, desktop: &Desktop, env: &Env, config: &Confi
         (bin.to_owned(), args.to_vec())
     };
 
-    let mut command = exec_cmd(bin, user, env);
+    let mut command = exec_cmd(bin, user, env, Some(session))?;
     command.args(args);
     Ok(command)
 }
 
-fn exec_cmd<T>(path: T, user: &User, env: &Env) -> Command
+/// Joins `argv` into the single argument taken by the distribution Xsession scripts.
+///
+/// Debian's script looks up the part before the first space with `command -v` and runs the rest
+/// with `exec $STARTUP`, so the line is only split on whitespace and must not be quoted.
+/// Fedora's script evaluates it, which handles the unquoted line the same way.
+fn xsession_command_line(argv: &[OsString]) -> OsString {
+    argv.join(OsStr::new(" "))
+}
+
+/// Runs `argv` with `<shell> -l -c 'exec ...'`, so that the profile of the user's shell is sourced.
+///
+/// Works with POSIX shells and fish. csh and tcsh are not supported, since tcsh only accepts `-l`
+/// as its sole argument.
+fn login_shell(user: &User, argv: &[OsString]) -> (OsString, Vec<OsString>) {
+    let shell = user.shell();
+    let fish = shell.file_name().is_some_and(|name| name == "fish");
+
+    let mut script = b"exec".to_vec();
+    for arg in argv {
+        script.push(b' ');
+        script.extend(shell_quote(arg, fish));
+    }
+
+    let args = vec!["-l".into(), "-c".into(), OsString::from_vec(script)];
+    (shell.as_os_str().to_owned(), args)
+}
+
+/// Quotes an argument in single quotes.
+///
+/// POSIX shells can't escape inside single quotes, so quotes are closed, escaped and reopened.
+/// fish instead allows escaping `'` and `\` inside them.
+fn shell_quote(arg: &OsStr, fish: bool) -> Vec<u8> {
+    let mut quoted = vec![b'\''];
+    for &c in arg.as_bytes() {
+        match c {
+            b'\'' if fish => quoted.extend(b"\\'"),
+            b'\\' if fish => quoted.extend(b"\\\\"),
+            b'\'' => quoted.extend(b"'\\''"),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push(b'\'');
+    quoted
+}
+
+/// Prepares a command which runs as the user with exactly the user's groups, optionally as the
+/// leader of a session group.
+fn exec_cmd<T>(path: T, user: &User, env: &Env, session: Option<&SessionGroup>) -> Result<Command>
 where
     T: AsRef<OsStr>,
 {
     let mut command = Command::new(path);
 
-    command.uid(user.uid()).gid(user.primary_group());
-    for (key, value) in &env.variables {
-        command.env(key, value);
+    // Pre-exec hooks run in order, so this one still runs as root
+    if let Some(session) = session {
+        session.enter(&mut command)?;
     }
-    command
+
+    // `Command::uid` would only drop the supplementary groups of root, and `Command::current_dir`
+    // would enter the home directory before dropping privileges
+    let (uid, gid, groups) = (user.uid(), user.primary_group(), user.groups()?);
+    let home_dir = CString::new(user.home_dir().as_os_str().as_bytes())?;
+    unsafe {
+        command.pre_exec(move || {
+            if libc::setgroups(groups.len(), groups.as_ptr()) < 0
+                || libc::setgid(gid) < 0
+                || libc::setuid(uid) < 0
+                || libc::chdir(home_dir.as_ptr()) < 0
+            {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(())
+        })
+    };
+    env.apply(&mut command);
+    Ok(command)
 }
 
-fn kill_process(pid: u32) {
-    unsafe { libc::kill(pid as i32, libc::SIGINT) };
+fn stop_process(child: &mut Child, name: &str, config: &Config) -> Option<Termination> {
+    match process::terminate(child, Duration::from_secs(config.termination_timeout)) {
+        Ok(termination) => {
+            println!("{} {}", name, termination);
+            Some(termination)
+        }
+        Err(e) => {
+            eprintln!("Failed to stop {}: {}", name, e);
+            None
+        }
+    }
 }
 
-fn wait_process(mut child: Child) {
-    if let Err(e) = child.wait() {
-        eprintln!("Failed to wait child process: {}", e);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::testing::fixture;
+
+    /// Arguments which a shell would split, expand or unescape without quoting.
+    const TRICKY_ARGS: [&str; 8] = [
+        "it's",
+        "'",
+        r"back\slash",
+        r"\'",
+        "two  words",
+        "",
+        "$HOME $(id)",
+        "line\nbreak",
+    ];
+
+    #[test]
+    fn login_shell_passes_arguments_unchanged() {
+        for shell in ["/bin/sh", "/bin/bash"] {
+            let user = User::sample(1000, "alice", "/nonexistent", shell);
+            let mut argv = vec![OsString::from("printf"), OsString::from("%s\\0")];
+            argv.extend(TRICKY_ARGS.iter().map(OsString::from));
+
+            let (bin, args) = login_shell(&user, &argv);
+            assert_eq!(bin, shell);
+            let output = Command::new(bin)
+                .args(args)
+                .env_clear()
+                .env("HOME", "/nonexistent")
+                .env("PATH", "/usr/bin:/bin")
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{}: {:?}", shell, output);
+
+            let expected = TRICKY_ARGS
+                .iter()
+                .flat_map(|arg| arg.bytes().chain([0]))
+                .collect::<Vec<_>>();
+            assert_eq!(output.stdout, expected, "{}", shell);
+        }
+    }
+
+    #[test]
+    fn login_shell_quotes_for_the_shell() {
+        let argv = ["startxfce4", "it's", r"back\slash"].map(OsString::from);
+
+        let sh = User::sample(1000, "alice", "/home/alice", "/bin/zsh");
+        let (_, args) = login_shell(&sh, &argv);
+        assert_eq!(args, ["-l", "-c", r"exec 'startxfce4' 'it'\''s' 'back\slash'"]);
+
+        // fish allows escaping inside single quotes, and needs backslashes escaped as well
+        let fish = User::sample(1000, "alice", "/home/alice", "/usr/bin/fish");
+        let (bin, args) = login_shell(&fish, &argv);
+        assert_eq!(bin, "/usr/bin/fish");
+        assert_eq!(args, ["-l", "-c", r"exec 'startxfce4' 'it\'s' 'back\\slash'"]);
+    }
+
+    #[test]
+    fn session_survives_the_xsession_argument_handling() {
+        let argv = ["env", "SESSION=startxfce4", "printenv", "SESSION"].map(OsString::from);
+        let output = Command::new("/bin/sh")
+            .arg(fixture("xsession/Xsession"))
+            .arg(xsession_command_line(&argv))
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "{:?}", output);
+        assert_eq!(output.stdout, b"startxfce4\n");
     }
 }
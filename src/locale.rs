@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use anyhow::Result;
+
+use crate::user::User;
+
+const LOCALE_DIR: &str = "/usr/lib/locale";
+const LOCALE_ARCHIVE_PATH: &str = "/usr/lib/locale/locale-archive";
+const SUPPORTED_PATH: &str = "/usr/share/i18n/SUPPORTED";
+/// Read in this order, so that systemd's `locale.conf` wins over Debian's `default/locale`.
+pub const LOCALE_PATHS: [&str; 2] = ["/etc/default/locale", "/etc/locale.conf"];
+const LAST_LOCALE_PATH: &str = ".cache/dsdmona/last_locale";
+
+pub const DEFAULT_LOCALE: &str = "C.UTF-8";
+
+const ARCHIVE_MAGIC: u32 = 0xde020109;
+/// Size of `struct namehashent`.
+const NAMEHASH_ENTRY_SIZE: u64 = 12;
+/// Limit for a name, which glibc keeps far shorter.
+const MAX_NAME_LEN: u64 = 256;
+
+/// Locales installed on the system, like `locale -a` lists them.
+///
+/// Falls back to the locales glibc supports if none are compiled.
+pub fn available() -> Vec<String> {
+    let mut locales = archive_locales().unwrap_or_default();
+
+    if let Ok(entries) = std::fs::read_dir(LOCALE_DIR) {
+        locales.extend(
+            entries
+                .flatten()
+                .filter(|entry| entry.path().join("LC_CTYPE").exists())
+                .filter_map(|entry| entry.file_name().into_string().ok()),
+        );
+    }
+
+    if locales.is_empty() {
+        if let Ok(supported) = std::fs::read_to_string(SUPPORTED_PATH) {
+            locales.extend(
+                supported
+                    .lines()
+                    .filter_map(|line| line.split_whitespace().next())
+                    .map(str::to_owned),
+            );
+        }
+    }
+
+    let mut seen = HashSet::new();
+    locales.retain(|locale| seen.insert(normalize(locale)));
+    locales.sort();
+    locales
+}
+
+/// `LANG` configured in the system locale files.
+pub fn system() -> Option<String> {
+    system_from(&LOCALE_PATHS)
+}
+
+/// `LANG` of the last of `paths` which sets it.
+fn system_from<P: AsRef<Path>>(paths: &[P]) -> Option<String> {
+    let mut lang = None;
+
+    for path in paths {
+        let Ok(data) = std::fs::read_to_string(path) else {
+            continue;
+        };
+        for line in data.lines() {
+            let line = line.trim();
+            let line = line.strip_prefix("export ").unwrap_or(line).trim_start();
+            if let Some(value) = line.strip_prefix("LANG=") {
+                lang = Some(value.trim().trim_matches(|c| c == '"' || c == '\'').to_owned());
+            }
+        }
+    }
+
+    lang.filter(|lang| !lang.is_empty())
+}
+
+/// Compares locale names, ignoring the spelling of the codeset (`UTF-8` vs `utf8`).
+pub fn is_same(a: &str, b: &str) -> bool {
+    normalize(a) == normalize(b)
+}
+
+fn normalize(locale: &str) -> String {
+    match locale.split_once('.') {
+        Some((language, codeset)) => {
+            let (codeset, modifier) = match codeset.split_once('@') {
+                Some((codeset, modifier)) => (codeset, Some(modifier)),
+                None => (codeset, None),
+            };
+            let codeset = codeset
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            match modifier {
+                Some(modifier) => format!("{}.{}@{}", language, codeset, modifier),
+                None => format!("{}.{}", language, codeset),
+            }
+        }
+        None => locale.to_owned(),
+    }
+}
+
+/// Names from the name hash table of glibc's `locale-archive`.
+fn archive_locales() -> Option<Vec<String>> {
+    let file = File::open(LOCALE_ARCHIVE_PATH).ok()?;
+    read_archive(&mut BufReader::new(file))
+}
+
+/// Reads the archive header and the name hash table, skipping the locale data itself.
+fn read_archive<R: BufRead + Seek>(archive: &mut R) -> Option<Vec<String>> {
+    // struct locarhead: magic, serial, namehash_offset, namehash_used, namehash_size, ...
+    let mut header = [0; 20];
+    archive.read_exact(&mut header).ok()?;
+    let read_u32 = |data: &[u8], offset: usize| u32::from_ne_bytes(data[offset..offset + 4].try_into().unwrap());
+
+    if read_u32(&header, 0) != ARCHIVE_MAGIC {
+        return None;
+    }
+    let namehash_offset = read_u32(&header, 8) as u64;
+    let namehash_size = read_u32(&header, 16) as u64;
+
+    // struct namehashent: hashval, name_offset, locrec_offset
+    archive.seek(SeekFrom::Start(namehash_offset)).ok()?;
+    let mut table = Vec::new();
+    archive
+        .by_ref()
+        .take(namehash_size * NAMEHASH_ENTRY_SIZE)
+        .read_to_end(&mut table)
+        .ok()?;
+    if table.len() as u64 != namehash_size * NAMEHASH_ENTRY_SIZE {
+        return None;
+    }
+
+    let mut locales = Vec::new();
+    for entry in table.chunks_exact(NAMEHASH_ENTRY_SIZE as usize) {
+        let name_offset = read_u32(entry, 4) as u64;
+        if name_offset == 0 {
+            continue;
+        }
+
+        archive.seek(SeekFrom::Start(name_offset)).ok()?;
+        let mut name = Vec::new();
+        archive.by_ref().take(MAX_NAME_LEN).read_until(0, &mut name).ok()?;
+        if name.pop() != Some(0) {
+            return None;
+        }
+        if let Ok(name) = String::from_utf8(name) {
+            locales.push(name);
+        }
+    }
+
+    Some(locales)
+}
+
+impl User {
+    pub fn get_last_locale(&self) -> Option<String> {
+        let _fs = self.fs_guard().ok()?;
+
+        let last_locale = std::fs::read_to_string(self.home_dir().join(LAST_LOCALE_PATH)).ok()?;
+        let last_locale = last_locale.trim();
+        (!last_locale.is_empty()).then(|| last_locale.to_owned())
+    }
+
+    pub fn set_last_locale(&self, locale: &str) -> Result<()> {
+        let _fs = self.fs_guard()?;
+
+        let path = self.home_dir().join(LAST_LOCALE_PATH);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(&path, locale)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    use crate::testing::fixture;
+
+    /// Builds an archive with a name hash table twice as large as needed, like glibc's.
+    fn archive(names: &[&str]) -> Vec<u8> {
+        let size = names.len() as u32 * 2;
+        let namehash_offset = 20;
+        let mut name_offset = namehash_offset + size * NAMEHASH_ENTRY_SIZE as u32;
+
+        let mut data = Vec::new();
+        for value in [ARCHIVE_MAGIC, 0, namehash_offset, names.len() as u32, size] {
+            data.extend(value.to_ne_bytes());
+        }
+        for i in 0..size as usize {
+            let offset = match names.get(i / 2) {
+                Some(name) if i % 2 == 0 => {
+                    let offset = name_offset;
+                    name_offset += name.len() as u32 + 1;
+                    offset
+                }
+                _ => 0,
+            };
+            for value in [0, offset, 0] {
+                data.extend(value.to_ne_bytes());
+            }
+        }
+        for name in names {
+            data.extend(name.as_bytes());
+            data.push(0);
+        }
+        data
+    }
+
+    #[test]
+    fn archive_names() {
+        let data = archive(&["de_DE.utf8", "en_US.utf8", "ru_RU.utf8"]);
+        assert_eq!(
+            read_archive(&mut Cursor::new(data)),
+            Some(vec![
+                "de_DE.utf8".to_owned(),
+                "en_US.utf8".to_owned(),
+                "ru_RU.utf8".to_owned()
+            ])
+        );
+        assert_eq!(read_archive(&mut Cursor::new(archive(&[]))), Some(vec![]));
+    }
+
+    #[test]
+    fn invalid_archives() {
+        let data = archive(&["de_DE.utf8", "en_US.utf8"]);
+
+        let mut wrong_magic = data.clone();
+        wrong_magic[0] ^= 1;
+        assert_eq!(read_archive(&mut Cursor::new(wrong_magic)), None);
+        // Truncated header, hash table and name
+        assert_eq!(read_archive(&mut Cursor::new(&data[..12])), None);
+        assert_eq!(read_archive(&mut Cursor::new(&data[..40])), None);
+        assert_eq!(read_archive(&mut Cursor::new(&data[..data.len() - 1])), None);
+    }
+
+    #[test]
+    fn normalized_names() {
+        assert_eq!(normalize("en_US.UTF-8"), "en_US.utf8");
+        assert_eq!(normalize("sr_RS.UTF-8@latin"), "sr_RS.utf8@latin");
+        assert_eq!(normalize("de_DE.ISO-8859-15"), "de_DE.iso885915");
+        assert_eq!(normalize("C"), "C");
+
+        assert!(is_same("en_US.UTF-8", "en_US.utf8"));
+        assert!(is_same("sr_RS.utf8@latin", "sr_RS.UTF-8@latin"));
+        assert!(!is_same("sr_RS.utf8@latin", "sr_RS.utf8"));
+        assert!(!is_same("en_US.UTF-8", "en_GB.UTF-8"));
+        assert!(!is_same("en_US", "en_US.UTF-8"));
+    }
+
+    #[test]
+    fn system_locale_files() {
+        let default_locale = fixture("env/default-locale");
+        let locale_conf = fixture("env/locale.conf");
+        let missing = fixture("locale/missing");
+
+        // The last file setting LANG wins
+        assert_eq!(
+            system_from(&[&default_locale, &locale_conf]).as_deref(),
+            Some("en_US.UTF-8")
+        );
+        assert_eq!(
+            system_from(&[&locale_conf, &default_locale]).as_deref(),
+            Some("en_GB.UTF-8")
+        );
+        assert_eq!(
+            system_from(&[&default_locale, &missing]).as_deref(),
+            Some("en_GB.UTF-8")
+        );
+        assert_eq!(
+            system_from(&[fixture("locale/exported")]).as_deref(),
+            Some("de_DE.UTF-8")
+        );
+        assert_eq!(system_from(&[&locale_conf, &fixture("locale/empty")]), None);
+        assert_eq!(system_from(&[missing]), None);
+    }
+}
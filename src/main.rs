@@ -1,25 +1,51 @@
+use std::path::{Path, PathBuf};
+
 use anyhow::Result;
 use argh::FromArgs;
 
-use dsdmona::{Config, LaunchType};
+use dsdmona::{AuthBackend, Config, LaunchType, DEFAULT_CONFIG_PATH};
 
 fn main() -> Result<()> {
     let app: App = argh::from_env();
 
-    dsdmona::login(Config {
-        tty: app.tty,
-        launch_type: app.launch_type,
-        auto_login_session: None,
-    })
+    let mut config = match &app.config {
+        Some(path) => Config::load(path)?,
+        None if Path::new(DEFAULT_CONFIG_PATH).exists() => Config::load(DEFAULT_CONFIG_PATH)?,
+        None => Config::default(),
+    };
+
+    if let Some(tty) = app.tty {
+        config.tty = tty;
+    }
+    if let Some(launch_type) = app.launch_type {
+        config.launch_type = launch_type;
+    }
+    if let Some(auth_backend) = app.auth_backend {
+        config.auth.backend = auth_backend;
+    }
+    if let Some(pam_service) = app.pam_service {
+        config.auth.service = pam_service;
+    }
+
+    dsdmona::login(config)
 }
 
 /// Dead simpla display manager.
 #[derive(FromArgs)]
 struct App {
+    /// path to the config file. /etc/dsdmona/config.toml (default).
+    #[argh(option)]
+    config: Option<PathBuf>,
     /// tty, where dsdmona will start.
     #[argh(option)]
-    tty: u8,
-    /// how to start the desktop. xinitrc (default) or dbus.
-    #[argh(option, default = "LaunchType::XInitRc")]
-    launch_type: LaunchType,
+    tty: Option<u8>,
+    /// how to start the desktop. xinitrc (default), dbus, shell, xsession or user-xsession.
+    #[argh(option)]
+    launch_type: Option<LaunchType>,
+    /// how to authenticate users. pam (default) or shadow.
+    #[argh(option)]
+    auth_backend: Option<AuthBackend>,
+    /// PAM service name. dsdmona (default).
+    #[argh(option)]
+    pam_service: Option<String>,
 }
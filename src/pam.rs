@@ -0,0 +1,326 @@
+use std::ffi::{CStr, CString, OsStr, OsString};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+use anyhow::Result;
+use libc::{c_char, c_int, c_void};
+use zeroize::Zeroizing;
+
+const PAM_SUCCESS: c_int = 0;
+const PAM_BUF_ERR: c_int = 5;
+const PAM_AUTH_ERR: c_int = 7;
+const PAM_CRED_INSUFFICIENT: c_int = 8;
+const PAM_USER_UNKNOWN: c_int = 10;
+const PAM_NEW_AUTHTOK_REQD: c_int = 12;
+const PAM_CONV_ERR: c_int = 19;
+
+const PAM_PROMPT_ECHO_OFF: c_int = 1;
+const PAM_PROMPT_ECHO_ON: c_int = 2;
+const PAM_ERROR_MSG: c_int = 3;
+const PAM_TEXT_INFO: c_int = 4;
+
+const PAM_ESTABLISH_CRED: c_int = 0x0002;
+const PAM_DELETE_CRED: c_int = 0x0004;
+const PAM_CHANGE_EXPIRED_AUTHTOK: c_int = 0x0020;
+const PAM_DATA_SILENT: c_int = 0x40000000;
+
+const PAM_TTY: c_int = 3;
+
+#[repr(C)]
+struct PamHandle {
+    _private: [u8; 0],
+}
+
+#[repr(C)]
+struct PamMessage {
+    msg_style: c_int,
+    msg: *const c_char,
+}
+
+#[repr(C)]
+struct PamResponse {
+    resp: *mut c_char,
+    resp_retcode: c_int,
+}
+
+type ConvFn = extern "C" fn(c_int, *mut *const PamMessage, *mut *mut PamResponse, *mut c_void) -> c_int;
+
+#[repr(C)]
+struct PamConv {
+    conv: ConvFn,
+    appdata_ptr: *mut c_void,
+}
+
+#[link(name = "pam")]
+extern "C" {
+    fn pam_start(
+        service_name: *const c_char,
+        user: *const c_char,
+        pam_conversation: *const PamConv,
+        pamh: *mut *mut PamHandle,
+    ) -> c_int;
+    fn pam_end(pamh: *mut PamHandle, pam_status: c_int) -> c_int;
+    fn pam_authenticate(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_acct_mgmt(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_chauthtok(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_setcred(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_open_session(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_close_session(pamh: *mut PamHandle, flags: c_int) -> c_int;
+    fn pam_set_item(pamh: *mut PamHandle, item_type: c_int, item: *const c_void) -> c_int;
+    fn pam_putenv(pamh: *mut PamHandle, name_value: *const c_char) -> c_int;
+    fn pam_getenvlist(pamh: *mut PamHandle) -> *mut *mut c_char;
+    fn pam_strerror(pamh: *mut PamHandle, errnum: c_int) -> *const c_char;
+}
+
+/// Renders PAM conversation messages to the user.
+pub trait Conversation {
+    fn prompt_echo_off(&mut self, message: &str) -> Result<Zeroizing<String>>;
+    fn prompt_echo_on(&mut self, message: &str) -> Result<String>;
+    fn info(&mut self, message: &str);
+    fn error(&mut self, message: &str);
+}
+
+/// A single PAM transaction.
+///
+/// Credentials and the session are released when the transaction is dropped.
+pub struct Pam<'a> {
+    handle: *mut PamHandle,
+    _conv: Box<PamConv>,
+    _conversation: Box<Box<dyn Conversation + 'a>>,
+    last_status: c_int,
+    credentials_established: bool,
+    session_opened: bool,
+    forked: bool,
+}
+
+impl<'a> Pam<'a> {
+    pub fn start(service: &str, user: &OsStr, conversation: Box<dyn Conversation + 'a>) -> Result<Self> {
+        let service = CString::new(service)?;
+        let user = CString::new(user.as_bytes())?;
+
+        let mut conversation = Box::new(conversation);
+        let conv = Box::new(PamConv {
+            conv: converse,
+            appdata_ptr: &mut *conversation as *mut Box<dyn Conversation + 'a> as *mut c_void,
+        });
+
+        let mut handle = std::ptr::null_mut();
+        let r = unsafe { pam_start(service.as_ptr(), user.as_ptr(), &*conv, &mut handle) };
+        anyhow::ensure!(
+            r == PAM_SUCCESS && !handle.is_null(),
+            "Failed to start PAM transaction for service {:?} (code: {})",
+            service,
+            r
+        );
+
+        Ok(Self {
+            handle,
+            _conv: conv,
+            _conversation: conversation,
+            last_status: PAM_SUCCESS,
+            credentials_established: false,
+            session_opened: false,
+            forked: false,
+        })
+    }
+
+    pub fn set_tty(&mut self, tty: &str) -> Result<()> {
+        self.set_item(PAM_TTY, tty)
+    }
+
+    /// Returns `false` if the user could not be authenticated and may try again.
+    pub fn authenticate(&mut self) -> Result<bool> {
+        let r = unsafe { pam_authenticate(self.handle, 0) };
+        match r {
+            PAM_AUTH_ERR | PAM_CRED_INSUFFICIENT | PAM_USER_UNKNOWN => {
+                self.last_status = r;
+                Ok(false)
+            }
+            _ => self.check(r, "authenticate").map(|_| true),
+        }
+    }
+
+    /// Checks that the account is valid, asking for a new password if it has expired.
+    pub fn acct_mgmt(&mut self) -> Result<()> {
+        let r = unsafe { pam_acct_mgmt(self.handle, 0) };
+        if r == PAM_NEW_AUTHTOK_REQD {
+            let r = unsafe { pam_chauthtok(self.handle, PAM_CHANGE_EXPIRED_AUTHTOK) };
+            return self.check(r, "change expired password");
+        }
+        self.check(r, "validate account")
+    }
+
+    pub fn putenv(&mut self, key: &str, value: &OsStr) -> Result<()> {
+        let mut name_value = OsString::from(key);
+        name_value.push("=");
+        name_value.push(value);
+        let name_value = CString::new(name_value.into_vec())?;
+
+        let r = unsafe { pam_putenv(self.handle, name_value.as_ptr()) };
+        self.check(r, "set environment variable")
+    }
+
+    /// Establishes credentials and opens the session.
+    pub fn open_session(&mut self) -> Result<()> {
+        let r = unsafe { pam_setcred(self.handle, PAM_ESTABLISH_CRED) };
+        self.check(r, "establish credentials")?;
+        self.credentials_established = true;
+
+        let r = unsafe { pam_open_session(self.handle, 0) };
+        self.check(r, "open session")?;
+        self.session_opened = true;
+
+        Ok(())
+    }
+
+    /// Closes the session and deletes credentials.
+    pub fn close_session(&mut self) -> Result<()> {
+        if self.session_opened {
+            self.session_opened = false;
+            let r = unsafe { pam_close_session(self.handle, 0) };
+            self.check(r, "close session")?;
+        }
+
+        if self.credentials_established {
+            self.credentials_established = false;
+            let r = unsafe { pam_setcred(self.handle, PAM_DELETE_CRED) };
+            self.check(r, "delete credentials")?;
+        }
+
+        Ok(())
+    }
+
+    /// Ends the transaction in the parent after a forked child took it over, without letting
+    /// modules clean up data which the child still uses.
+    pub fn end_after_fork(mut self) {
+        self.forked = true;
+    }
+
+    /// Environment variables set by PAM modules (e.g. `XDG_SESSION_ID` from pam_systemd).
+    pub fn env_list(&self) -> Vec<(String, OsString)> {
+        let list = unsafe { pam_getenvlist(self.handle) };
+        if list.is_null() {
+            return Vec::new();
+        }
+
+        let mut result = Vec::new();
+        unsafe {
+            let mut item = list;
+            while !(*item).is_null() {
+                let bytes = CStr::from_ptr(*item).to_bytes();
+                if let Some(split) = bytes.iter().position(|&c| c == b'=') {
+                    let key = String::from_utf8_lossy(&bytes[..split]).into_owned();
+                    let value = OsStr::from_bytes(&bytes[split + 1..]).to_owned();
+                    result.push((key, value));
+                }
+                libc::free(*item as *mut c_void);
+                item = item.add(1);
+            }
+            libc::free(list as *mut c_void);
+        }
+        result
+    }
+
+    fn set_item(&mut self, item_type: c_int, value: &str) -> Result<()> {
+        let value = CString::new(value)?;
+        let r = unsafe { pam_set_item(self.handle, item_type, value.as_ptr() as *const c_void) };
+        self.check(r, "set item")
+    }
+
+    fn check(&mut self, r: c_int, action: &str) -> Result<()> {
+        self.last_status = r;
+        if r == PAM_SUCCESS {
+            return Ok(());
+        }
+
+        let message = unsafe { CStr::from_ptr(pam_strerror(self.handle, r)) };
+        anyhow::bail!("PAM failed to {}: {}", action, message.to_string_lossy())
+    }
+}
+
+impl Drop for Pam<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.close_session() {
+            eprintln!("{}", e);
+        }
+        let status = match self.forked {
+            true => self.last_status | PAM_DATA_SILENT,
+            false => self.last_status,
+        };
+        unsafe { pam_end(self.handle, status) };
+    }
+}
+
+extern "C" fn converse(
+    num_msg: c_int,
+    msg: *mut *const PamMessage,
+    resp: *mut *mut PamResponse,
+    appdata_ptr: *mut c_void,
+) -> c_int {
+    if num_msg <= 0 || msg.is_null() || resp.is_null() || appdata_ptr.is_null() {
+        return PAM_CONV_ERR;
+    }
+    let num_msg = num_msg as usize;
+
+    let conversation = unsafe { &mut *(appdata_ptr as *mut Box<dyn Conversation>) };
+
+    let responses = unsafe { libc::calloc(num_msg, std::mem::size_of::<PamResponse>()) as *mut PamResponse };
+    if responses.is_null() {
+        return PAM_BUF_ERR;
+    }
+
+    for i in 0..num_msg {
+        let message = unsafe { &**msg.add(i) };
+        let text = match message.msg.is_null() {
+            true => Default::default(),
+            false => unsafe { CStr::from_ptr(message.msg) }.to_string_lossy(),
+        };
+
+        let reply = match message.msg_style {
+            PAM_PROMPT_ECHO_OFF => conversation
+                .prompt_echo_off(&text)
+                .and_then(|reply| Ok(Zeroizing::new(CString::new(reply.as_bytes())?))),
+            PAM_PROMPT_ECHO_ON => conversation
+                .prompt_echo_on(&text)
+                .and_then(|reply| Ok(Zeroizing::new(CString::new(reply)?))),
+            PAM_ERROR_MSG => {
+                conversation.error(&text);
+                continue;
+            }
+            PAM_TEXT_INFO => {
+                conversation.info(&text);
+                continue;
+            }
+            _ => Err(anyhow::Error::msg("Unknown PAM message style")),
+        };
+
+        let reply = match reply {
+            Ok(reply) => unsafe { libc::strdup(reply.as_ptr()) },
+            Err(e) => {
+                eprintln!("PAM conversation failed: {}", e);
+                unsafe { free_responses(responses, num_msg) };
+                return PAM_CONV_ERR;
+            }
+        };
+        if reply.is_null() {
+            unsafe { free_responses(responses, num_msg) };
+            return PAM_BUF_ERR;
+        }
+
+        unsafe { (*responses.add(i)).resp = reply };
+    }
+
+    unsafe { *resp = responses };
+    PAM_SUCCESS
+}
+
+unsafe fn free_responses(responses: *mut PamResponse, count: usize) {
+    for i in 0..count {
+        let reply = (*responses.add(i)).resp;
+        if !reply.is_null() {
+            let len = libc::strlen(reply);
+            std::ptr::write_bytes(reply, 0, len);
+            libc::free(reply as *mut c_void);
+        }
+    }
+    libc::free(responses as *mut c_void);
+}
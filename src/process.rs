@@ -0,0 +1,477 @@
+use std::fmt;
+use std::fs::OpenOptions;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// The stage at which a terminated process stopped.
+pub enum Termination {
+    /// The process had already exited on its own.
+    Exited(ExitStatus),
+    /// The process stopped after `SIGTERM`.
+    Terminated(ExitStatus),
+    /// The process ignored `SIGTERM` for the whole grace period.
+    Killed(ExitStatus),
+}
+
+impl fmt::Display for Termination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(status) => write!(f, "exited with {}", status),
+            Self::Terminated(status) => write!(f, "stopped after SIGTERM with {}", status),
+            Self::Killed(status) => write!(f, "killed with SIGKILL ({})", status),
+        }
+    }
+}
+
+/// Sends `SIGTERM` to the child and escalates to `SIGKILL` if it is still alive after `timeout`.
+pub fn terminate(child: &mut Child, timeout: Duration) -> Result<Termination> {
+    if let Some(status) = child.try_wait()? {
+        return Ok(Termination::Exited(status));
+    }
+
+    signal(child, libc::SIGTERM)?;
+
+    if let Event::Exited(_) = wait_any(&[child.id() as libc::pid_t], None, Some(timeout))? {
+        return Ok(Termination::Terminated(child.wait()?));
+    }
+
+    // The child is not reaped yet, so its pid can't be reused
+    signal(child, libc::SIGKILL)?;
+    Ok(Termination::Killed(child.wait()?))
+}
+
+fn signal(child: &Child, signal: libc::c_int) -> Result<()> {
+    let r = unsafe { libc::kill(child.id() as libc::pid_t, signal) };
+    anyhow::ensure!(
+        r == 0,
+        "Failed to send signal {} to {}: {}",
+        signal,
+        child.id(),
+        std::io::Error::last_os_error()
+    );
+    Ok(())
+}
+
+/// A file descriptor which becomes readable once the process has exited.
+struct PidFd(OwnedFd);
+
+impl PidFd {
+    /// Returns `None` on kernels without `pidfd_open` (before Linux 5.3).
+    fn open(pid: libc::pid_t) -> Result<Option<Self>> {
+        let fd = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, 0) };
+        if fd < 0 {
+            let e = std::io::Error::last_os_error();
+            anyhow::ensure!(
+                e.raw_os_error() == Some(libc::ENOSYS),
+                "Failed to open pidfd of {}: {}",
+                pid,
+                e
+            );
+            return Ok(None);
+        }
+
+        Ok(Some(Self(unsafe { OwnedFd::from_raw_fd(fd as RawFd) })))
+    }
+}
+
+/// Blocks the given signals and reports them through a `signalfd` instead.
+///
+/// Signals are only read from the descriptor by [`SignalFd::clear`], so a pending signal
+/// otherwise still reaches its handler once the previous signal mask is restored on drop.
+pub struct SignalFd {
+    fd: OwnedFd,
+    old_mask: libc::sigset_t,
+}
+
+impl SignalFd {
+    pub fn block(signals: &[libc::c_int]) -> Result<Self> {
+        unsafe {
+            let mut mask = std::mem::zeroed::<libc::sigset_t>();
+            libc::sigemptyset(&mut mask);
+            for &signal in signals {
+                libc::sigaddset(&mut mask, signal);
+            }
+
+            let mut old_mask = std::mem::zeroed::<libc::sigset_t>();
+            let r = libc::pthread_sigmask(libc::SIG_BLOCK, &mask, &mut old_mask);
+            anyhow::ensure!(r == 0, "Failed to block signals");
+
+            let fd = libc::signalfd(-1, &mask, libc::SFD_CLOEXEC | libc::SFD_NONBLOCK);
+            if fd < 0 {
+                let e = std::io::Error::last_os_error();
+                libc::pthread_sigmask(libc::SIG_SETMASK, &old_mask, std::ptr::null_mut());
+                anyhow::bail!("Failed to create signalfd: {}", e);
+            }
+
+            Ok(Self {
+                fd: OwnedFd::from_raw_fd(fd),
+                old_mask,
+            })
+        }
+    }
+
+    /// Discards the pending signals.
+    pub fn clear(&self) {
+        let mut info = std::mem::MaybeUninit::<libc::signalfd_siginfo>::uninit();
+        let size = std::mem::size_of::<libc::signalfd_siginfo>();
+        while unsafe { libc::read(self.fd.as_raw_fd(), info.as_mut_ptr() as *mut libc::c_void, size) } > 0 {}
+    }
+}
+
+impl Drop for SignalFd {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_sigmask(libc::SIG_SETMASK, &self.old_mask, std::ptr::null_mut()) };
+    }
+}
+
+pub enum Event {
+    /// The process with the given index has exited. It still has to be reaped.
+    Exited(usize),
+    /// One of the blocked signals is pending.
+    Signal,
+    Timeout,
+}
+
+/// Waits until one of the child processes exits or a signal arrives.
+///
+/// Exits are detected through pidfds, or through `SIGCHLD` on kernels without them.
+pub fn wait_any(pids: &[libc::pid_t], signals: Option<&SignalFd>, timeout: Option<Duration>) -> Result<Event> {
+    let mut pidfds = Vec::new();
+    for &pid in pids {
+        match PidFd::open(pid)? {
+            Some(pidfd) => pidfds.push(pidfd),
+            None => return wait_any_sigchld(pids, signals, timeout),
+        }
+    }
+
+    let fds = pidfds.iter().map(|pidfd| pidfd.0.as_raw_fd()).collect::<Vec<_>>();
+    poll(&fds, signals, timeout)
+}
+
+fn wait_any_sigchld(pids: &[libc::pid_t], signals: Option<&SignalFd>, timeout: Option<Duration>) -> Result<Event> {
+    // Blocked before checking the processes, so that no exit can be missed in between
+    let sigchld = SignalFd::block(&[libc::SIGCHLD])?;
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(i) = pids.iter().position(|&pid| has_exited(pid)) {
+            return Ok(Event::Exited(i));
+        }
+
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        match poll(&[sigchld.fd.as_raw_fd()], signals, timeout)? {
+            Event::Exited(_) => sigchld.clear(),
+            Event::Timeout => {
+                // Another thread may have taken the signal
+                return Ok(match pids.iter().position(|&pid| has_exited(pid)) {
+                    Some(i) => Event::Exited(i),
+                    None => Event::Timeout,
+                });
+            }
+            Event::Signal => return Ok(Event::Signal),
+        }
+    }
+}
+
+/// Whether the child has exited, without reaping it.
+fn has_exited(pid: libc::pid_t) -> bool {
+    let mut info = unsafe { std::mem::zeroed::<libc::siginfo_t>() };
+    let options = libc::WEXITED | libc::WNOHANG | libc::WNOWAIT;
+    let r = unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, options) };
+    r == 0 && unsafe { info.si_pid() } != 0
+}
+
+/// Polls `fds`, which report [`Event::Exited`], and the signals.
+fn poll(fds: &[RawFd], signals: Option<&SignalFd>, timeout: Option<Duration>) -> Result<Event> {
+    let mut pollfds = fds
+        .iter()
+        .copied()
+        .chain(signals.map(|signals| signals.fd.as_raw_fd()))
+        .map(|fd| libc::pollfd {
+            fd,
+            events: libc::POLLIN,
+            revents: 0,
+        })
+        .collect::<Vec<_>>();
+
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        let timeout = match deadline {
+            Some(deadline) => deadline.saturating_duration_since(Instant::now()).as_millis() as libc::c_int,
+            None => -1,
+        };
+
+        let r = unsafe { libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout) };
+        if r < 0 {
+            let e = std::io::Error::last_os_error();
+            anyhow::ensure!(e.kind() == std::io::ErrorKind::Interrupted, "Failed to poll: {}", e);
+            continue;
+        }
+        if r == 0 {
+            return Ok(Event::Timeout);
+        }
+
+        if let Some(i) = pollfds[..fds.len()].iter().position(|pollfd| pollfd.revents != 0) {
+            return Ok(Event::Exited(i));
+        }
+        return Ok(Event::Signal);
+    }
+}
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+const KILL_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// All processes started by a session.
+///
+/// The session leader gets its own process group and, when cgroup v2 is available, the session is
+/// also tracked by a cgroup, which catches processes that left the process group:
+/// - the logind scope which pam_systemd moved the session worker into, and which the session
+///   inherits from it
+/// - without a logind session, a cgroup below the (delegated) service of dsdmona
+pub struct SessionGroup {
+    cgroup: Option<PathBuf>,
+    /// The cgroup is a scope of logind, which also contains the session worker.
+    scope: bool,
+    pgid: Option<libc::pid_t>,
+}
+
+impl SessionGroup {
+    /// Must be created by the session worker after the PAM session has been opened.
+    ///
+    /// `service` is the cgroup of dsdmona, read before the worker was forked.
+    pub fn new(service: Option<&Path>, name: &str) -> Self {
+        let (cgroup, scope) = match service {
+            Some(service) => match own_cgroup() {
+                Ok(own) if own != service => (Ok(own), true),
+                _ => (create_cgroup(service, name), false),
+            },
+            None => (Err(anyhow::anyhow!("cgroup v2 is not available")), false),
+        };
+
+        let cgroup = match cgroup {
+            Ok(cgroup) => Some(cgroup),
+            Err(e) => {
+                eprintln!("Tracking session by process group only: {:#}", e);
+                None
+            }
+        };
+
+        Self {
+            cgroup,
+            scope,
+            pgid: None,
+        }
+    }
+
+    /// Makes the command start in a new session and process group, and in the cgroup unless it
+    /// inherits the logind scope anyway.
+    ///
+    /// Must be called before privileges are dropped, joining the cgroup needs root.
+    pub fn enter(&self, command: &mut Command) -> Result<()> {
+        let procs = match &self.cgroup {
+            Some(cgroup) if !self.scope => Some(OpenOptions::new().write(true).open(cgroup.join("cgroup.procs"))?),
+            _ => None,
+        };
+
+        unsafe {
+            command.pre_exec(move || {
+                if libc::setsid() < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                if let Some(procs) = &procs {
+                    if libc::write(procs.as_raw_fd(), b"0".as_ptr() as *const libc::c_void, 1) < 0 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                }
+                Ok(())
+            })
+        };
+        Ok(())
+    }
+
+    /// Spawns the session leader, which has to be prepared with [`SessionGroup::enter`].
+    pub fn spawn(&mut self, command: &mut Command) -> Result<Child> {
+        let child = command.spawn()?;
+        self.pgid = Some(child.id() as libc::pid_t);
+        Ok(child)
+    }
+
+    /// Sends `SIGTERM` to every remaining process, then `SIGKILL` to those still alive after `timeout`.
+    pub fn terminate(&self, timeout: Duration) {
+        let pids = self.pids();
+        if pids.is_empty() {
+            return;
+        }
+        println!("Terminating leftover session processes: {}", describe(&pids));
+        self.signal(libc::SIGTERM);
+
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            if self.pids().is_empty() {
+                return;
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        }
+
+        let pids = self.pids();
+        if !pids.is_empty() {
+            println!("Killing session processes which ignored SIGTERM: {}", describe(&pids));
+            self.signal(libc::SIGKILL);
+        }
+
+        // Give the kernel a moment to tear the processes down, so that the cgroup can be removed
+        let deadline = Instant::now() + KILL_TIMEOUT;
+        while !self.pids().is_empty() && Instant::now() < deadline {
+            std::thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        if let Some(cgroup) = &self.cgroup {
+            // `cgroup.kill` only exists since Linux 5.14, and would kill the worker in a scope
+            if signal == libc::SIGKILL && !self.scope && std::fs::write(cgroup.join("cgroup.kill"), "1").is_ok() {
+                return;
+            }
+            for pid in self.pids() {
+                unsafe { libc::kill(pid, signal) };
+            }
+        }
+        if let Some(pgid) = self.pgid {
+            unsafe { libc::kill(-pgid, signal) };
+        }
+    }
+
+    /// Processes in the cgroup and the process group of the session, except for the worker.
+    fn pids(&self) -> Vec<libc::pid_t> {
+        let mut pids = Vec::new();
+
+        if let Some(cgroup) = &self.cgroup {
+            if let Ok(procs) = std::fs::read_to_string(cgroup.join("cgroup.procs")) {
+                let worker = std::process::id() as libc::pid_t;
+                pids.extend(
+                    procs
+                        .lines()
+                        .filter_map(|pid| pid.parse::<libc::pid_t>().ok())
+                        .filter(|&pid| pid != worker),
+                );
+            }
+        }
+
+        if let Some(pgid) = self.pgid {
+            let entries = std::fs::read_dir("/proc").into_iter().flatten().flatten();
+            for pid in entries.filter_map(|entry| entry.file_name().to_str()?.parse::<libc::pid_t>().ok()) {
+                if !pids.contains(&pid) && unsafe { libc::getpgid(pid) } == pgid && !is_zombie(pid) {
+                    pids.push(pid);
+                }
+            }
+        }
+
+        pids.sort_unstable();
+        pids
+    }
+}
+
+impl Drop for SessionGroup {
+    fn drop(&mut self) {
+        // systemd removes the scope once it is empty
+        if let Some(cgroup) = self.cgroup.as_ref().filter(|_| !self.scope) {
+            if let Err(e) = std::fs::remove_dir(cgroup) {
+                eprintln!("Failed to remove cgroup {}: {}", cgroup.display(), e);
+            }
+        }
+    }
+}
+
+/// The cgroup v2 the calling process is in.
+pub fn own_cgroup() -> Result<PathBuf> {
+    let root = Path::new(CGROUP_ROOT);
+    anyhow::ensure!(root.join("cgroup.controllers").exists(), "cgroup v2 is not mounted");
+
+    let own = std::fs::read_to_string("/proc/self/cgroup")?;
+    let Some(own) = own.lines().find_map(|line| line.strip_prefix("0::")) else {
+        anyhow::bail!("Failed to find own cgroup");
+    };
+
+    Ok(root.join(own.trim_start_matches('/')))
+}
+
+fn create_cgroup(parent: &Path, name: &str) -> Result<PathBuf> {
+    let cgroup = parent.join(name);
+    match std::fs::create_dir(&cgroup) {
+        Ok(()) => {}
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e).with_context(|| format!("Failed to create cgroup {}", cgroup.display())),
+    }
+
+    Ok(cgroup)
+}
+
+/// Zombies keep their process group until they are reaped, but can't be signalled anymore.
+fn is_zombie(pid: libc::pid_t) -> bool {
+    match std::fs::read_to_string(format!("/proc/{}/stat", pid)) {
+        Ok(stat) => stat
+            .rsplit_once(')')
+            .is_some_and(|(_, rest)| rest.trim_start().starts_with('Z')),
+        Err(_) => true,
+    }
+}
+
+fn describe(pids: &[libc::pid_t]) -> String {
+    pids.iter()
+        .map(|pid| match std::fs::read_to_string(format!("/proc/{}/comm", pid)) {
+            Ok(comm) => format!("{} ({})", pid, comm.trim_end()),
+            Err(_) => pid.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spawn(script: &str) -> Child {
+        Command::new("/bin/sh").arg("-c").arg(script).spawn().unwrap()
+    }
+
+    #[test]
+    fn terminate_stops_with_sigterm() {
+        let mut child = spawn("exec sleep 10");
+        let termination = terminate(&mut child, Duration::from_secs(5)).unwrap();
+        assert!(matches!(termination, Termination::Terminated(_)));
+    }
+
+    #[test]
+    fn terminate_escalates_to_sigkill() {
+        let mut child = spawn("trap '' TERM; while :; do sleep 0.01; done");
+        // Let the shell install the trap
+        std::thread::sleep(Duration::from_millis(100));
+        let termination = terminate(&mut child, Duration::from_millis(200)).unwrap();
+        assert!(matches!(termination, Termination::Killed(_)));
+    }
+
+    #[test]
+    fn sigchld_fallback_reports_exits_without_reaping() {
+        let mut children = [spawn("exec sleep 10"), spawn("exit 3")];
+        let pids = children
+            .iter()
+            .map(|child| child.id() as libc::pid_t)
+            .collect::<Vec<_>>();
+
+        let event = wait_any_sigchld(&pids, None, Some(Duration::from_secs(5))).unwrap();
+        assert!(matches!(event, Event::Exited(1)));
+        assert_eq!(children[1].wait().unwrap().code(), Some(3));
+
+        let event = wait_any_sigchld(&pids[..1], None, Some(Duration::from_millis(50))).unwrap();
+        assert!(matches!(event, Event::Timeout));
+        children[0].kill().unwrap();
+        children[0].wait().unwrap();
+    }
+}
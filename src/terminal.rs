@@ -0,0 +1,85 @@
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// Waits for a single key press on stdin.
+///
+/// Returns `false` if the timeout has elapsed. The pressed key is discarded.
+pub fn wait_key(timeout: Duration) -> Result<bool> {
+    let fd = libc::STDIN_FILENO;
+
+    let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+    let r = unsafe { libc::tcgetattr(fd, &mut termios) };
+    anyhow::ensure!(r == 0, "Failed to get terminal attributes");
+
+    let original = termios;
+    termios.c_lflag &= !(libc::ICANON | libc::ECHO);
+    let r = unsafe { libc::tcsetattr(fd, libc::TCSANOW, &termios) };
+    anyhow::ensure!(r == 0, "Failed to set terminal attributes");
+
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let r = unsafe { libc::poll(&mut pollfd, 1, timeout.as_millis() as i32) };
+
+    unsafe {
+        libc::tcflush(fd, libc::TCIFLUSH);
+        libc::tcsetattr(fd, libc::TCSANOW, &original);
+    }
+
+    anyhow::ensure!(r >= 0, "Failed to poll terminal");
+    Ok(r > 0)
+}
+
+const KDSETMODE: libc::c_ulong = 0x4B3A;
+const KDGETMODE: libc::c_ulong = 0x4B3B;
+const KDGKBMODE: libc::c_ulong = 0x4B44;
+const KDSKBMODE: libc::c_ulong = 0x4B45;
+const KD_TEXT: libc::c_int = 0x00;
+const K_UNICODE: libc::c_int = 0x03;
+
+/// Display, keyboard and line settings of the greeter's VT.
+///
+/// Sessions change them and may not restore them, e.g. a crashed Wayland compositor leaves the
+/// VT in graphics mode with the keyboard turned off.
+pub struct VtState {
+    termios: Option<libc::termios>,
+    keyboard_mode: libc::c_int,
+}
+
+impl VtState {
+    pub fn save() -> Self {
+        let fd = libc::STDIN_FILENO;
+
+        let mut termios = unsafe { std::mem::zeroed::<libc::termios>() };
+        let termios = (unsafe { libc::tcgetattr(fd, &mut termios) } == 0).then_some(termios);
+
+        let mut keyboard_mode: libc::c_int = K_UNICODE;
+        let mut display_mode: libc::c_int = KD_TEXT;
+        unsafe {
+            // Only a text mode VT is worth saving, otherwise fall back to the defaults
+            if libc::ioctl(fd, KDGETMODE as _, &mut display_mode) < 0
+                || display_mode != KD_TEXT
+                || libc::ioctl(fd, KDGKBMODE as _, &mut keyboard_mode) < 0
+            {
+                keyboard_mode = K_UNICODE;
+            }
+        }
+
+        Self { termios, keyboard_mode }
+    }
+
+    /// Errors are ignored, stdin doesn't have to be a VT.
+    pub fn restore(&self) {
+        let fd = libc::STDIN_FILENO;
+        unsafe {
+            libc::ioctl(fd, KDSETMODE as _, KD_TEXT);
+            libc::ioctl(fd, KDSKBMODE as _, self.keyboard_mode);
+            if let Some(termios) = &self.termios {
+                libc::tcsetattr(fd, libc::TCSANOW, termios);
+            }
+        }
+    }
+}
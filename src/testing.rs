@@ -0,0 +1,10 @@
+//! Helpers shared by the unit tests.
+
+use std::path::{Path, PathBuf};
+
+const FIXTURES: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+
+/// Path of a file in `tests/fixtures`.
+pub fn fixture(path: &str) -> PathBuf {
+    Path::new(FIXTURES).join(path)
+}
@@ -26,9 +26,9 @@ impl User {
         unsafe { Self::new(libc::getuid()).unwrap() }
     }
 
-    pub fn all() -> Vec<Self> {
+    pub fn all(min_uid: uid_t, max_uid: uid_t) -> Vec<Self> {
         let iter = unsafe { AllUsers::new() };
-        iter.filter(|user| user.uid >= MIN_UID && user.uid < MAX_UID).collect()
+        iter.filter(|user| user.uid >= min_uid && user.uid < max_uid).collect()
     }
 
     pub fn new(uid: uid_t) -> Result<Self> {
@@ -52,6 +52,41 @@ impl User {
         Ok(unsafe { cpasswd_to_user(result.read()) })
     }
 
+    pub fn from_name(name: &str) -> Result<Self> {
+        let name = CString::new(name)?;
+
+        let mut passwd = unsafe { std::mem::zeroed::<c_passwd>() };
+        let mut buf = vec![0; 2048];
+        let mut result = std::ptr::null_mut::<c_passwd>();
+
+        loop {
+            let r = unsafe { libc::getpwnam_r(name.as_ptr(), &mut passwd, buf.as_mut_ptr(), buf.len(), &mut result) };
+
+            if r != libc::ERANGE {
+                break;
+            }
+
+            let newsize = buf.len() * 2;
+            buf.resize(newsize, 0);
+        }
+
+        anyhow::ensure!(result == &mut passwd, "User {:?} not found", name);
+
+        Ok(unsafe { cpasswd_to_user(result.read()) })
+    }
+
+    /// A user which doesn't have to exist in the user database.
+    #[cfg(test)]
+    pub fn sample(uid: uid_t, name: &str, home_dir: &str, shell: &str) -> Self {
+        Self {
+            uid,
+            primary_group: uid,
+            name: Arc::from(OsStr::new(name)),
+            home_dir: home_dir.into(),
+            shell: shell.into(),
+        }
+    }
+
     pub fn uid(&self) -> uid_t {
         self.uid
     }
@@ -72,32 +107,32 @@ impl User {
         Path::new(&self.shell)
     }
 
-    pub fn use_for_fs<F, R>(&self, f: F) -> R
-    where
-        F: FnOnce() -> R,
-    {
-        unsafe fn set_fs_user(uid: uid_t, group: gid_t) {
-            libc::setfsuid(uid);
-            libc::setgid(group);
-        }
+    /// The primary group followed by all supplementary groups of the user.
+    pub fn groups(&self) -> Result<Vec<gid_t>> {
+        let name = CString::new(self.name.as_bytes())?;
 
-        unsafe {
-            // Get current user group
-            let current_user = libc::getpwuid(libc::getuid());
-            assert!(!current_user.is_null());
-            let current_uid = (*current_user).pw_uid;
-            let current_group = (*current_user).pw_gid;
+        let mut groups = vec![0; 32];
+        loop {
+            let mut count = groups.len() as libc::c_int;
+            let r = unsafe { libc::getgrouplist(name.as_ptr(), self.primary_group, groups.as_mut_ptr(), &mut count) };
 
-            // Set user as fs owner
-            set_fs_user(self.uid, self.primary_group);
-            let res = f();
-            set_fs_user(current_uid, current_group);
+            if r >= 0 {
+                groups.truncate(count as usize);
+                return Ok(groups);
+            }
 
-            // Done
-            res
+            // `count` holds the required size, but only since glibc 2.3.3
+            let newsize = (count as usize).max(groups.len() * 2);
+            groups.resize(newsize, 0);
         }
     }
 
+    /// Switches the filesystem uid, gid and supplementary groups of dsdmona to the user until
+    /// the returned guard is dropped.
+    pub fn fs_guard(&self) -> Result<FsGuard> {
+        FsGuard::new(self.uid, self.primary_group, self.groups()?)
+    }
+
     pub fn set_file_owner<T: AsRef<Path>>(&self, path: T) -> Result<()> {
         let path = CString::new(path.as_ref().as_os_str().as_bytes())?;
         let r = unsafe { libc::chown(path.as_ptr(), self.uid, self.primary_group) };
@@ -158,6 +193,82 @@ impl User {
     }
 }
 
+/// Filesystem credentials of a user, see [`User::fs_guard`].
+///
+/// Only the filesystem ids are changed, so the previous credentials can always be restored.
+pub struct FsGuard {
+    uid: uid_t,
+    gid: gid_t,
+    groups: Vec<gid_t>,
+}
+
+impl FsGuard {
+    fn new(uid: uid_t, gid: gid_t, groups: Vec<gid_t>) -> Result<Self> {
+        let mut guard = Self {
+            uid: unsafe { libc::geteuid() },
+            gid: unsafe { libc::getegid() },
+            groups: current_groups()?,
+        };
+
+        // Dropping the guard on error restores whatever has been switched already
+        set_groups(&groups)?;
+        guard.gid = set_fsgid(gid)?;
+        guard.uid = set_fsuid(uid)?;
+
+        Ok(guard)
+    }
+}
+
+impl Drop for FsGuard {
+    fn drop(&mut self) {
+        let restored = set_fsuid(self.uid)
+            .and_then(|_| set_fsgid(self.gid))
+            .and_then(|_| set_groups(&self.groups));
+        if let Err(e) = restored {
+            eprintln!("Failed to restore filesystem credentials: {}", e);
+        }
+    }
+}
+
+/// Returns the previous filesystem uid.
+fn set_fsuid(uid: uid_t) -> Result<uid_t> {
+    // `setfsuid` can't report errors, so the second call is used to check the first one
+    let previous = unsafe { libc::setfsuid(uid) } as uid_t;
+    let current = unsafe { libc::setfsuid(uid) } as uid_t;
+    anyhow::ensure!(current == uid, "Failed to set filesystem uid to {}", uid);
+    Ok(previous)
+}
+
+/// Returns the previous filesystem gid.
+fn set_fsgid(gid: gid_t) -> Result<gid_t> {
+    let previous = unsafe { libc::setfsgid(gid) } as gid_t;
+    let current = unsafe { libc::setfsgid(gid) } as gid_t;
+    anyhow::ensure!(current == gid, "Failed to set filesystem gid to {}", gid);
+    Ok(previous)
+}
+
+fn set_groups(groups: &[gid_t]) -> Result<()> {
+    let r = unsafe { libc::setgroups(groups.len(), groups.as_ptr()) };
+    anyhow::ensure!(
+        r == 0,
+        "Failed to set supplementary groups: {}",
+        std::io::Error::last_os_error()
+    );
+    Ok(())
+}
+
+fn current_groups() -> Result<Vec<gid_t>> {
+    let count = unsafe { libc::getgroups(0, std::ptr::null_mut()) };
+    anyhow::ensure!(count >= 0, "Failed to get supplementary groups");
+
+    let mut groups = vec![0; count as usize];
+    let count = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+    anyhow::ensure!(count >= 0, "Failed to get supplementary groups");
+    groups.truncate(count as usize);
+
+    Ok(groups)
+}
+
 struct AllUsers;
 
 impl AllUsers {
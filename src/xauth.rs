@@ -0,0 +1,255 @@
+use std::convert::TryFrom;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use anyhow::Result;
+use zeroize::Zeroizing;
+
+const FAMILY_LOCAL: u16 = 256;
+const FAMILY_WILD: u16 = 65535;
+
+const MIT_MAGIC_COOKIE: &[u8] = b"MIT-MAGIC-COOKIE-1";
+const COOKIE_LEN: usize = 16;
+
+pub struct Cookie(Zeroizing<[u8; COOKIE_LEN]>);
+
+impl Cookie {
+    /// Generates a new MIT-MAGIC-COOKIE-1 from the kernel RNG.
+    pub fn generate() -> Result<Self> {
+        let mut cookie = Zeroizing::new([0; COOKIE_LEN]);
+
+        let mut filled = 0;
+        while filled < COOKIE_LEN {
+            let r = unsafe {
+                libc::getrandom(
+                    cookie[filled..].as_mut_ptr() as *mut libc::c_void,
+                    COOKIE_LEN - filled,
+                    0,
+                )
+            };
+            if r < 0 {
+                let e = std::io::Error::last_os_error();
+                anyhow::ensure!(
+                    e.kind() == std::io::ErrorKind::Interrupted,
+                    "Failed to generate cookie: {}",
+                    e
+                );
+                continue;
+            }
+            filled += r as usize;
+        }
+
+        Ok(Self(cookie))
+    }
+}
+
+/// Entries of an Xauthority file.
+pub struct XAuthority {
+    entries: Vec<XAuthEntry>,
+}
+
+impl XAuthority {
+    /// Entries for the local hostname and any address of the display.
+    pub fn for_display(cookie: &Cookie, display: u32) -> Result<Self> {
+        let number = display.to_string().into_bytes();
+
+        Ok(Self {
+            entries: vec![
+                XAuthEntry::new(FAMILY_LOCAL, hostname()?, number.clone(), cookie),
+                XAuthEntry::new(FAMILY_WILD, Vec::new(), number, cookie),
+            ],
+        })
+    }
+
+    /// Entry for the server's `-auth` file. The server ignores address and display number.
+    pub fn for_server(cookie: &Cookie) -> Self {
+        Self {
+            entries: vec![XAuthEntry::new(FAMILY_WILD, Vec::new(), Vec::new(), cookie)],
+        }
+    }
+
+    /// Replaces the file with a new one, readable only by its owner.
+    ///
+    /// An existing file or symlink is removed rather than followed, and the new file is only
+    /// created if nothing was put in its place since.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        match std::fs::remove_file(&path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .custom_flags(libc::O_NOFOLLOW)
+            .mode(0o600)
+            .open(path)?;
+
+        let mut writer = BufWriter::new(file);
+        self.write_to(&mut writer)?;
+        writer.flush()?;
+
+        Ok(())
+    }
+
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        for entry in &self.entries {
+            entry.write_to(writer)?;
+        }
+        Ok(())
+    }
+}
+
+struct XAuthEntry {
+    family: u16,
+    address: Vec<u8>,
+    number: Vec<u8>,
+    name: &'static [u8],
+    data: Zeroizing<Vec<u8>>,
+}
+
+impl XAuthEntry {
+    fn new(family: u16, address: Vec<u8>, number: Vec<u8>, cookie: &Cookie) -> Self {
+        Self {
+            family,
+            address,
+            number,
+            name: MIT_MAGIC_COOKIE,
+            data: Zeroizing::new(cookie.0.to_vec()),
+        }
+    }
+
+    /// Every field is stored as a big-endian `u16` length followed by the bytes.
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        writer.write_all(&self.family.to_be_bytes())?;
+        for field in [&self.address[..], &self.number[..], self.name, &self.data[..]] {
+            let len = u16::try_from(field.len())?;
+            writer.write_all(&len.to_be_bytes())?;
+            writer.write_all(field)?;
+        }
+        Ok(())
+    }
+}
+
+fn hostname() -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; 256];
+    let r = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    anyhow::ensure!(r == 0, "Failed to get hostname");
+
+    let len = buf.iter().position(|&c| c == 0).unwrap_or(buf.len());
+    buf.truncate(len);
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::PermissionsExt;
+
+    /// `(family, address, number, name, data)`
+    type Entry = (u16, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>);
+
+    /// Parses entries like libXau does.
+    fn parse(mut data: &[u8]) -> Vec<Entry> {
+        let read_u16 = |data: &mut &[u8]| {
+            let value = u16::from_be_bytes([data[0], data[1]]);
+            *data = &data[2..];
+            value
+        };
+        let read_field = |data: &mut &[u8]| {
+            let len = u16::from_be_bytes([data[0], data[1]]) as usize;
+            let field = data[2..2 + len].to_vec();
+            *data = &data[2 + len..];
+            field
+        };
+
+        let mut entries = Vec::new();
+        while !data.is_empty() {
+            let family = read_u16(&mut data);
+            let address = read_field(&mut data);
+            let number = read_field(&mut data);
+            let name = read_field(&mut data);
+            let cookie = read_field(&mut data);
+            entries.push((family, address, number, name, cookie));
+        }
+        entries
+    }
+
+    fn cookie() -> Cookie {
+        Cookie(Zeroizing::new(*b"0123456789abcdef"))
+    }
+
+    fn serialize(xauthority: &XAuthority) -> Vec<u8> {
+        let mut data = Vec::new();
+        xauthority.write_to(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn display_entries_round_trip() {
+        let xauthority = XAuthority::for_display(&cookie(), 12).unwrap();
+        let entries = parse(&serialize(&xauthority));
+
+        assert_eq!(
+            entries,
+            vec![
+                (
+                    FAMILY_LOCAL,
+                    hostname().unwrap(),
+                    b"12".to_vec(),
+                    MIT_MAGIC_COOKIE.to_vec(),
+                    b"0123456789abcdef".to_vec()
+                ),
+                (
+                    FAMILY_WILD,
+                    Vec::new(),
+                    b"12".to_vec(),
+                    MIT_MAGIC_COOKIE.to_vec(),
+                    b"0123456789abcdef".to_vec()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn server_entry_layout() {
+        let data = serialize(&XAuthority::for_server(&cookie()));
+
+        let mut expected = vec![0xff, 0xff, 0, 0, 0, 0, 0, 18];
+        expected.extend(MIT_MAGIC_COOKIE);
+        expected.extend([0, 16]);
+        expected.extend(b"0123456789abcdef");
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn generated_cookies_differ() {
+        let (a, b) = (Cookie::generate().unwrap(), Cookie::generate().unwrap());
+        assert_ne!(a.0[..], b.0[..]);
+    }
+
+    #[test]
+    fn write_replaces_symlinks_instead_of_following_them() {
+        let dir = std::env::temp_dir().join(format!("dsdmona-xauth-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let target = dir.join("target");
+        let path = dir.join(".Xauthority");
+        std::fs::write(&target, "untouched").unwrap();
+        std::os::unix::fs::symlink(&target, &path).unwrap();
+
+        let xauthority = XAuthority::for_server(&cookie());
+        xauthority.write(&path).unwrap();
+
+        let metadata = std::fs::symlink_metadata(&path).unwrap();
+        let contents = (std::fs::read(&path).unwrap(), std::fs::read_to_string(&target).unwrap());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(metadata.file_type().is_file());
+        assert_eq!(metadata.permissions().mode() & 0o777, 0o600);
+        assert_eq!(contents, (serialize(&xauthority), "untouched".to_owned()));
+    }
+}
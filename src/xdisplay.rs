@@ -1,38 +1,37 @@
 use std::ffi::CString;
-use std::path::Path;
-use std::time::Duration;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd};
+use std::os::unix::process::CommandExt;
+use std::process::{Child, Command};
+use std::time::{Duration, Instant};
 
 use anyhow::Result;
 use x11::xlib;
 
+use crate::process::{self, Event, SignalFd};
+
 pub struct XDisplay {
     display: *mut xlib::Display,
 }
 
 impl XDisplay {
-    pub fn find_free_xdisplay() -> Option<u8> {
-        for i in 0..32 {
-            let lock = format!("/tmp/.X{}-lock", i);
-            if !Path::new(&lock).exists() {
-                return Some(i);
-            }
-        }
-        None
-    }
-
     pub fn open(display_name: &str) -> Result<Self> {
         let display_name = CString::new(display_name)?;
 
-        for _ in 0..50 {
-            let display = unsafe { xlib::XOpenDisplay(display_name.as_ptr()) };
-            if !display.is_null() {
-                return Ok(Self { display });
-            } else {
-                std::thread::sleep(Duration::from_millis(50));
-            }
-        }
+        let display = unsafe { xlib::XOpenDisplay(display_name.as_ptr()) };
+        anyhow::ensure!(!display.is_null(), "Failed to open X Display {:?}", display_name);
 
-        anyhow::bail!("Failed to open X Display")
+        Ok(Self { display })
+    }
+
+    /// Closes the connection to a server which is already gone.
+    ///
+    /// `XCloseDisplay` would try to flush the connection and exit the process from the IO error
+    /// handler, so only the socket is closed and the Xlib structures are leaked.
+    pub fn abandon(self) {
+        unsafe { libc::close(xlib::XConnectionNumber(self.display)) };
+        std::mem::forget(self);
     }
 }
 
@@ -41,3 +40,130 @@ impl Drop for XDisplay {
         unsafe { xlib::XCloseDisplay(self.display) };
     }
 }
+
+/// Pipe through which the X server reports the display number it has bound (`-displayfd`).
+pub struct DisplayFd {
+    reader: File,
+    writer: OwnedFd,
+}
+
+impl DisplayFd {
+    pub fn new() -> Result<Self> {
+        let mut fds = [0; 2];
+        let r = unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+        anyhow::ensure!(r == 0, "Failed to create displayfd pipe");
+
+        Ok(Self {
+            reader: unsafe { File::from_raw_fd(fds[0]) },
+            writer: unsafe { OwnedFd::from_raw_fd(fds[1]) },
+        })
+    }
+
+    /// Adds `-displayfd` to the server command and lets the server inherit the write end.
+    pub fn pass_to(&self, command: &mut Command) {
+        let fd = self.writer.as_raw_fd();
+        command.arg("-displayfd").arg(fd.to_string());
+        unsafe {
+            command.pre_exec(move || match libc::fcntl(fd, libc::F_SETFD, 0) {
+                -1 => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            })
+        };
+    }
+
+    /// Waits until the server has bound a display and returns its number.
+    ///
+    /// The server only reports the display once it accepts connections, which makes this the
+    /// readiness notification of servers that can't signal dsdmona.
+    pub fn read(self, timeout: Duration) -> Result<u32> {
+        let Self { reader, writer } = self;
+        drop(writer);
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            let mut pollfd = libc::pollfd {
+                fd: reader.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            let remaining = deadline.saturating_duration_since(Instant::now()).as_millis() as libc::c_int;
+            let r = unsafe { libc::poll(&mut pollfd, 1, remaining) };
+            if r < 0 {
+                let e = std::io::Error::last_os_error();
+                anyhow::ensure!(
+                    e.kind() == std::io::ErrorKind::Interrupted,
+                    "Failed to poll displayfd: {}",
+                    e
+                );
+                continue;
+            }
+            anyhow::ensure!(
+                r > 0,
+                "X server did not report its display within {}s",
+                timeout.as_secs()
+            );
+            break;
+        }
+
+        let mut output = String::new();
+        BufReader::new(reader).read_line(&mut output)?;
+
+        let display = output.trim();
+        anyhow::ensure!(!display.is_empty(), "X server exited without reporting its display");
+        Ok(display.parse()?)
+    }
+}
+
+/// SIGUSR1 which the X server sends to its parent once it accepts connections,
+/// provided that it was started with SIGUSR1 ignored.
+pub struct ReadySignal {
+    signals: Option<SignalFd>,
+    old_action: libc::sigaction,
+}
+
+impl ReadySignal {
+    /// Must be registered before the server is spawned.
+    ///
+    /// SIGUSR1 is ignored meanwhile, so that signals after the first one, which the server sends
+    /// whenever it resets, can't kill dsdmona.
+    pub fn register() -> Result<Self> {
+        let signals = SignalFd::block(&[libc::SIGUSR1])?;
+
+        let mut old_action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        let mut action = unsafe { std::mem::zeroed::<libc::sigaction>() };
+        action.sa_sigaction = libc::SIG_IGN;
+        let r = unsafe { libc::sigaction(libc::SIGUSR1, &action, &mut old_action) };
+        anyhow::ensure!(r == 0, "Failed to ignore SIGUSR1: {}", std::io::Error::last_os_error());
+
+        Ok(Self {
+            signals: Some(signals),
+            old_action,
+        })
+    }
+
+    pub fn request_from(&self, command: &mut Command) {
+        unsafe {
+            command.pre_exec(|| match libc::signal(libc::SIGUSR1, libc::SIG_IGN) {
+                libc::SIG_ERR => Err(std::io::Error::last_os_error()),
+                _ => Ok(()),
+            })
+        };
+    }
+
+    pub fn wait(&self, server: &mut Child, timeout: Duration) -> Result<()> {
+        let pid = server.id() as libc::pid_t;
+        match process::wait_any(&[pid], self.signals.as_ref(), Some(timeout))? {
+            Event::Signal => Ok(()),
+            Event::Exited(_) => anyhow::bail!("X server exited during startup with {}", server.wait()?),
+            Event::Timeout => anyhow::bail!("X server did not become ready within {}s", timeout.as_secs()),
+        }
+    }
+}
+
+impl Drop for ReadySignal {
+    fn drop(&mut self) {
+        // Unblocked while still ignored, which discards a pending signal
+        drop(self.signals.take());
+        unsafe { libc::sigaction(libc::SIGUSR1, &self.old_action, std::ptr::null_mut()) };
+    }
+}